@@ -0,0 +1,187 @@
+//! Per-arena operations.
+//!
+//! Operations in this module take the index of the arena they apply to. Some of them also accept
+//! [`ALL`] to apply to every arena at once.
+//!
+//! [`ALL`]: constant.ALL.html
+use std::io;
+use std::os::raw::{c_char, c_uint};
+
+use {call_mib, get_mib, name_to_mib, set_mib};
+
+/// An arena index referring to all arenas at once.
+///
+/// This corresponds to `MALLCTL_ARENAS_ALL` in jemalloc's API.
+pub const ALL: c_uint = 4096;
+
+const PURGE: *const c_char = b"arena.0.purge\0" as *const _ as *const _;
+
+/// Purges all unused dirty pages for an arena, or for all arenas if `arena` is [`ALL`].
+///
+/// This corresponds to `arena.<i>.purge` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::arena::purge(jemalloc_ctl::arena::ALL).unwrap();
+/// }
+/// ```
+///
+/// [`ALL`]: constant.ALL.html
+pub fn purge(arena: c_uint) -> io::Result<()> {
+    Purge::new()?.purge(arena)
+}
+
+/// A type providing the ability to purge all unused dirty pages for an arena.
+///
+/// This corresponds to `arena.<i>.purge` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::arena::{self, Purge};
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let purge = Purge::new().unwrap();
+///
+///     purge.purge(arena::ALL).unwrap();
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct Purge([usize; 3]);
+
+impl Purge {
+    /// Returns a new `Purge`.
+    pub fn new() -> io::Result<Purge> {
+        let mut mib = [0; 3];
+        unsafe {
+            name_to_mib(PURGE, &mut mib)?;
+        }
+        Ok(Purge(mib))
+    }
+
+    /// Purges all unused dirty pages for an arena, or for all arenas if `arena` is [`ALL`].
+    ///
+    /// [`ALL`]: constant.ALL.html
+    pub fn purge(&self, arena: c_uint) -> io::Result<()> {
+        let mut mib = self.0;
+        mib[1] = arena as usize;
+        unsafe { call_mib(&mib) }
+    }
+}
+
+const DIRTY_DECAY_MS: *const c_char = b"arena.0.dirty_decay_ms\0" as *const _ as *const _;
+
+/// Returns the approximate time in milliseconds an unused dirty page in an arena lives before it
+/// is purged.
+///
+/// A value of -1 indicates that purging is disabled.
+///
+/// This corresponds to `arena.<i>.dirty_decay_ms` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     println!("arena 0 dirty decay: {}ms", jemalloc_ctl::arena::dirty_decay_ms(0).unwrap());
+/// }
+/// ```
+pub fn dirty_decay_ms(arena: c_uint) -> io::Result<isize> {
+    DirtyDecayMs::new()?.get(arena)
+}
+
+/// Sets the approximate time in milliseconds an unused dirty page in an arena lives before it is
+/// purged.
+///
+/// Each time this is set, all currently unused dirty pages are considered to have fully decayed,
+/// which causes them to be purged immediately unless the new value is -1.
+///
+/// This corresponds to `arena.<i>.dirty_decay_ms` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::arena::set_dirty_decay_ms(0, 1000).unwrap();
+///     assert_eq!(jemalloc_ctl::arena::dirty_decay_ms(0).unwrap(), 1000);
+/// }
+/// ```
+pub fn set_dirty_decay_ms(arena: c_uint, dirty_decay_ms: isize) -> io::Result<()> {
+    DirtyDecayMs::new()?.set(arena, dirty_decay_ms)
+}
+
+/// A type providing access to the approximate time in milliseconds an unused dirty page in an
+/// arena lives before it is purged.
+///
+/// This corresponds to `arena.<i>.dirty_decay_ms` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::arena::DirtyDecayMs;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let dirty_decay_ms = DirtyDecayMs::new().unwrap();
+///
+///     dirty_decay_ms.set(0, 1000).unwrap();
+///     assert_eq!(dirty_decay_ms.get(0).unwrap(), 1000);
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct DirtyDecayMs([usize; 3]);
+
+impl DirtyDecayMs {
+    /// Returns a new `DirtyDecayMs`.
+    pub fn new() -> io::Result<DirtyDecayMs> {
+        let mut mib = [0; 3];
+        unsafe {
+            name_to_mib(DIRTY_DECAY_MS, &mut mib)?;
+        }
+        Ok(DirtyDecayMs(mib))
+    }
+
+    /// Returns the dirty page decay time of an arena.
+    pub fn get(&self, arena: c_uint) -> io::Result<isize> {
+        let mut mib = self.0;
+        mib[1] = arena as usize;
+        unsafe { get_mib(&mib) }
+    }
+
+    /// Sets the dirty page decay time of an arena.
+    pub fn set(&self, arena: c_uint, dirty_decay_ms: isize) -> io::Result<()> {
+        let mut mib = self.0;
+        mib[1] = arena as usize;
+        unsafe { set_mib(&mib, dirty_decay_ms) }
+    }
+}
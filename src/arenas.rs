@@ -1,8 +1,11 @@
 //! Arena operations.
+use jemalloc_sys;
 use std::io;
-use std::os::raw::{c_char, c_uint};
+use std::mem;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::sync::OnceLock;
 
-use {get, get_mib, name_to_mib};
+use {cvt, get, get_mib, name_to_mib, set, set_mib};
 
 const NARENAS: *const c_char = b"arenas.narenas\0" as *const _ as *const _;
 
@@ -62,3 +65,328 @@ impl NArenas {
         unsafe { get_mib(&self.0) }
     }
 }
+
+const DIRTY_DECAY_MS: *const c_char = b"arenas.dirty_decay_ms\0" as *const _ as *const _;
+
+/// Returns the dirty page decay time with which new arenas are initialized.
+///
+/// A value of -1 indicates that purging is disabled.
+///
+/// This corresponds to `arenas.dirty_decay_ms` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     println!("default dirty decay: {}ms", jemalloc_ctl::arenas::dirty_decay_ms().unwrap());
+/// }
+/// ```
+pub fn dirty_decay_ms() -> io::Result<isize> {
+    unsafe { get(DIRTY_DECAY_MS) }
+}
+
+/// Sets the dirty page decay time with which new arenas are initialized.
+///
+/// Existing arenas are not affected; see [`arena::set_dirty_decay_ms`].
+///
+/// This corresponds to `arenas.dirty_decay_ms` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let previous = jemalloc_ctl::arenas::dirty_decay_ms().unwrap();
+///     jemalloc_ctl::arenas::set_dirty_decay_ms(1000).unwrap();
+///     assert_eq!(jemalloc_ctl::arenas::dirty_decay_ms().unwrap(), 1000);
+///     jemalloc_ctl::arenas::set_dirty_decay_ms(previous).unwrap();
+/// }
+/// ```
+///
+/// [`arena::set_dirty_decay_ms`]: ../arena/fn.set_dirty_decay_ms.html
+pub fn set_dirty_decay_ms(dirty_decay_ms: isize) -> io::Result<()> {
+    unsafe { set(DIRTY_DECAY_MS, dirty_decay_ms) }
+}
+
+/// A type providing access to the dirty page decay time with which new arenas are initialized.
+///
+/// This corresponds to `arenas.dirty_decay_ms` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::arenas::DirtyDecayMs;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let dirty_decay_ms = DirtyDecayMs::new().unwrap();
+///
+///     println!("default dirty decay: {}ms", dirty_decay_ms.get().unwrap());
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct DirtyDecayMs([usize; 2]);
+
+impl DirtyDecayMs {
+    /// Returns a new `DirtyDecayMs`.
+    pub fn new() -> io::Result<DirtyDecayMs> {
+        unsafe {
+            let mut mib = [0; 2];
+            name_to_mib(DIRTY_DECAY_MS, &mut mib)?;
+            Ok(DirtyDecayMs(mib))
+        }
+    }
+
+    /// Returns the dirty page decay time with which new arenas are initialized.
+    pub fn get(&self) -> io::Result<isize> {
+        unsafe { get_mib(&self.0) }
+    }
+
+    /// Sets the dirty page decay time with which new arenas are initialized.
+    pub fn set(&self, dirty_decay_ms: isize) -> io::Result<()> {
+        unsafe { set_mib(&self.0, dirty_decay_ms) }
+    }
+}
+
+const LOOKUP: *const c_char = b"arenas.lookup\0" as *const _ as *const _;
+
+/// Returns the index of the arena which owns an allocation.
+///
+/// This corresponds to `arenas.lookup` in jemalloc's API.
+///
+/// # Safety
+///
+/// `ptr` must be the pointer returned by the jemalloc call which made a live allocation, not a
+/// pointer into the middle of it.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let value = Box::new(0u64);
+///     let arena = unsafe { jemalloc_ctl::arenas::lookup(&*value as *const u64 as *const u8) };
+///     println!("allocated in arena {}", arena.unwrap());
+/// }
+/// ```
+pub unsafe fn lookup(ptr: *const u8) -> io::Result<c_uint> {
+    let mut arena = 0;
+    let mut len = mem::size_of::<c_uint>();
+    let mut ptr = ptr as *const c_void;
+    cvt(jemalloc_sys::mallctl(
+        LOOKUP,
+        &mut arena as *mut _ as *mut _,
+        &mut len,
+        &mut ptr as *mut _ as *mut _,
+        mem::size_of::<*const c_void>(),
+    ))?;
+    Ok(arena)
+}
+
+/// A type providing access to the arena which owns an allocation.
+///
+/// This corresponds to `arenas.lookup` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::arenas::Lookup;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let lookup = Lookup::new().unwrap();
+///
+///     let value = Box::new(0u64);
+///     let arena = unsafe { lookup.get(&*value as *const u64 as *const u8) };
+///     println!("allocated in arena {}", arena.unwrap());
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct Lookup([usize; 2]);
+
+impl Lookup {
+    /// Returns a new `Lookup`.
+    pub fn new() -> io::Result<Lookup> {
+        unsafe {
+            let mut mib = [0; 2];
+            name_to_mib(LOOKUP, &mut mib)?;
+            Ok(Lookup(mib))
+        }
+    }
+
+    /// Returns the index of the arena which owns an allocation.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be the pointer returned by the jemalloc call which made a live allocation, not a
+    /// pointer into the middle of it.
+    pub unsafe fn get(&self, ptr: *const u8) -> io::Result<c_uint> {
+        let mut arena = 0;
+        let mut len = mem::size_of::<c_uint>();
+        let mut ptr = ptr as *const c_void;
+        cvt(jemalloc_sys::mallctlbymib(
+            self.0.as_ptr(),
+            self.0.len(),
+            &mut arena as *mut _ as *mut _,
+            &mut len,
+            &mut ptr as *mut _ as *mut _,
+            mem::size_of::<*const c_void>(),
+        ))?;
+        Ok(arena)
+    }
+}
+
+const NBINS: *const c_char = b"arenas.nbins\0" as *const _ as *const _;
+const BIN_SIZE: *const c_char = b"arenas.bin.0.size\0" as *const _ as *const _;
+
+/// The size class of an allocation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SizeClass {
+    /// A small size class, whose allocations are packed into slabs.
+    Small {
+        /// The index of the size class's bin.
+        bin: c_uint,
+    },
+    /// A large size class, whose allocations each have their own extent.
+    Large,
+}
+
+/// Information about where an allocation lives.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// The index of the arena which owns the allocation.
+    pub arena: c_uint,
+
+    /// The usable size of the allocation, which is the size of its size class.
+    pub usable_size: usize,
+
+    /// The size class of the allocation.
+    pub size_class: SizeClass,
+}
+
+/// Returns the arena, usable size, and size class of an allocation.
+///
+/// This is useful to determine which of several arenas an object actually lives in, and how much
+/// memory it occupies.
+///
+/// This corresponds to `arenas.lookup`, `arenas.nbins`, and `arenas.bin.<i>.size` in jemalloc's
+/// API, and to `sallocx`.
+///
+/// # Safety
+///
+/// `ptr` must be the pointer returned by the jemalloc call which made a live allocation, not a
+/// pointer into the middle of it.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::arenas::{self, SizeClass};
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let buf = vec![0u8; 100];
+///     let location = unsafe { arenas::locate(buf.as_ptr()).unwrap() };
+///     assert!(location.usable_size >= 100);
+///     assert!(matches!(location.size_class, SizeClass::Small { .. }));
+/// }
+/// ```
+pub unsafe fn locate(ptr: *const u8) -> io::Result<Location> {
+    let arena = lookup(ptr)?;
+    let usable_size = jemalloc_sys::sallocx(ptr as *const c_void, 0);
+
+    let size_class = match bin_sizes()?.binary_search(&usable_size) {
+        Ok(bin) => SizeClass::Small { bin: bin as c_uint },
+        Err(_) => SizeClass::Large,
+    };
+
+    Ok(Location {
+        arena,
+        usable_size,
+        size_class,
+    })
+}
+
+// the sizes of the small size classes in increasing order, which are fixed once jemalloc is
+// initialized
+fn bin_sizes() -> io::Result<&'static [usize]> {
+    static BIN_SIZES: OnceLock<Vec<usize>> = OnceLock::new();
+
+    if let Some(sizes) = BIN_SIZES.get() {
+        return Ok(sizes);
+    }
+
+    let sizes = unsafe {
+        let nbins = get::<c_uint>(NBINS)?;
+        let mut mib = [0; 4];
+        name_to_mib(BIN_SIZE, &mut mib)?;
+        (0..nbins)
+            .map(|bin| {
+                mib[2] = bin as usize;
+                get_mib::<usize>(&mib)
+            })
+            .collect::<io::Result<Vec<_>>>()?
+    };
+    Ok(BIN_SIZES.get_or_init(|| sizes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn locate_size_classes() {
+        let small = Box::new([0u8; 100]);
+        let large = vec![0u8; 1 << 20];
+
+        unsafe {
+            let location = locate(small.as_ptr()).unwrap();
+            assert_eq!(location.arena, lookup(small.as_ptr()).unwrap());
+            assert!(location.usable_size >= 100);
+            let bin = match location.size_class {
+                SizeClass::Small { bin } => bin,
+                SizeClass::Large => panic!("expected a small size class"),
+            };
+            assert!(bin < get::<c_uint>(NBINS).unwrap());
+            assert_eq!(bin_sizes().unwrap()[bin as usize], location.usable_size);
+
+            let location = locate(large.as_ptr()).unwrap();
+            assert!(location.usable_size >= 1 << 20);
+            assert_eq!(location.size_class, SizeClass::Large);
+            assert_eq!(
+                Lookup::new().unwrap().get(large.as_ptr()).unwrap(),
+                location.arena
+            );
+        }
+    }
+}
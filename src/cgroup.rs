@@ -0,0 +1,558 @@
+//! cgroup v2 memory limit awareness.
+//!
+//! Processes running in containers are typically subject to a cgroup memory limit, and are killed
+//! when they exceed it. jemalloc does not know about that limit, and may hold on to unused dirty
+//! pages which count against it. This module reads the limits of the process's cgroup, reports
+//! them alongside jemalloc's own statistics, and provides a [`Policy`] which releases memory back
+//! to the operating system as the limit approaches.
+//!
+//! Only the unified (v2) cgroup hierarchy is supported.
+//!
+//! [`Policy`]: struct.Policy.html
+use libc::{self, c_uint};
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use arena;
+use arenas;
+use epoch;
+use stats;
+
+/// The memory limits and usage of a cgroup.
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    /// The hard limit in bytes, or `None` if there is none.
+    ///
+    /// This corresponds to `memory.max`.
+    pub max: Option<u64>,
+
+    /// The throttling limit in bytes, or `None` if there is none.
+    ///
+    /// This corresponds to `memory.high`.
+    pub high: Option<u64>,
+
+    /// The total number of bytes currently charged to the cgroup.
+    ///
+    /// This includes memory not allocated through jemalloc, such as the page cache.
+    ///
+    /// This corresponds to `memory.current`.
+    pub current: u64,
+}
+
+impl Limits {
+    /// Returns the lower of the `max` and `high` limits, or `None` if neither is set.
+    pub fn limit(&self) -> Option<u64> {
+        match (self.max, self.high) {
+            (Some(max), Some(high)) => Some(max.min(high)),
+            (max, high) => max.or(high),
+        }
+    }
+
+    /// Returns the number of bytes that can be charged to the cgroup before it reaches its limit,
+    /// or `None` if it has no limit.
+    pub fn headroom(&self) -> Option<u64> {
+        self.limit().map(|limit| limit.saturating_sub(self.current))
+    }
+
+    /// Returns the fraction of the limit currently in use, or `None` if the cgroup has no limit.
+    pub fn usage(&self) -> Option<f64> {
+        self.limit().map(|limit| self.current as f64 / limit as f64)
+    }
+}
+
+/// Returns the memory limits and usage of the current process's cgroup.
+///
+/// An error of kind `NotFound` is returned if the process is not in a cgroup v2 hierarchy.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let limits = jemalloc_ctl::cgroup::limits().unwrap();
+///     println!("{} bytes in use, limit {:?}", limits.current, limits.limit());
+/// }
+/// ```
+pub fn limits() -> io::Result<Limits> {
+    let dir = cgroup_dir()?;
+    Ok(Limits {
+        max: parse_limit(&read(&dir, "memory.max")?)?,
+        high: parse_limit(&read(&dir, "memory.high")?)?,
+        current: parse_u64(&read(&dir, "memory.current")?)?,
+    })
+}
+
+/// Returns the number of bytes that can be charged to the current process's cgroup before it
+/// reaches its limit, or `None` if it has no limit.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     match jemalloc_ctl::cgroup::headroom().unwrap() {
+///         Some(headroom) => println!("{} bytes of headroom", headroom),
+///         None => println!("no memory limit"),
+///     }
+/// }
+/// ```
+pub fn headroom() -> io::Result<Option<u64>> {
+    limits().map(|l| l.headroom())
+}
+
+/// A snapshot of the cgroup's memory limits alongside jemalloc's view of memory usage.
+#[derive(Copy, Clone, Debug)]
+pub struct Report {
+    /// The limits and usage of the cgroup.
+    pub limits: Limits,
+
+    /// The total number of bytes in physically resident data pages mapped by jemalloc.
+    ///
+    /// See [`stats::resident`] for more information.
+    ///
+    /// [`stats::resident`]: ../stats/fn.resident.html
+    pub resident: usize,
+}
+
+/// Returns the current process's cgroup memory limits alongside jemalloc's resident memory.
+///
+/// The jemalloc epoch is advanced before reading statistics so that they are consistent with the
+/// cgroup's values.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let report = jemalloc_ctl::cgroup::report().unwrap();
+///     println!(
+///         "{} bytes resident in jemalloc, {} bytes charged to cgroup",
+///         report.resident, report.limits.current,
+///     );
+/// }
+/// ```
+pub fn report() -> io::Result<Report> {
+    epoch()?;
+    let resident = stats::resident()?;
+    let limits = limits()?;
+    Ok(Report { limits, resident })
+}
+
+/// The action taken by [`Policy::apply`].
+///
+/// [`Policy::apply`]: struct.Policy.html#method.apply
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Usage was below all thresholds, or the cgroup has no limit.
+    None,
+    /// Usage exceeded the decay threshold, so arenas use the lowered dirty page decay time.
+    Decay,
+    /// Usage dropped below the decay threshold, so the original dirty page decay times were
+    /// restored.
+    Restore,
+    /// All unused dirty pages were purged.
+    Purge,
+}
+
+/// A policy releasing memory back to the operating system as the cgroup limit approaches.
+///
+/// Thresholds are expressed as a fraction of the cgroup limit as returned by [`Limits::usage`].
+/// Policies are constructed with `Policy::default()`, and the fields then set individually.
+///
+/// [`Limits::usage`]: struct.Limits.html#method.usage
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::cgroup::Policy;
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let mut policy = Policy::default();
+///     policy.purge_threshold = Some(0.95);
+///
+///     loop {
+///         policy.apply().unwrap();
+///         thread::sleep(Duration::from_secs(1));
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Policy {
+    /// The usage above which the dirty page decay time of all arenas is lowered to `decay_ms`.
+    ///
+    /// Defaults to `Some(0.8)`.
+    pub decay_threshold: Option<f64>,
+
+    /// The dirty page decay time applied when usage exceeds `decay_threshold`.
+    ///
+    /// The original decay times are restored once usage drops back below the threshold. Defaults
+    /// to 1000.
+    pub decay_ms: isize,
+
+    /// The usage above which all unused dirty pages are purged.
+    ///
+    /// Defaults to `Some(0.9)`.
+    pub purge_threshold: Option<f64>,
+
+    saved: Option<SavedDecay>,
+}
+
+// the decay times in effect before the policy lowered them
+#[derive(Clone, Debug)]
+struct SavedDecay {
+    default: isize,
+    arenas: Vec<(c_uint, Option<isize>)>,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            decay_threshold: Some(0.8),
+            decay_ms: 1000,
+            purge_threshold: Some(0.9),
+            saved: None,
+        }
+    }
+}
+
+impl Policy {
+    /// Checks the current process's cgroup usage, and releases memory if it exceeds a threshold.
+    ///
+    /// This should be called periodically. Decay times are only changed when usage crosses the
+    /// decay threshold, since each change purges all unused dirty pages of an arena. If the purge
+    /// threshold is exceeded, a purge is performed on every call.
+    pub fn apply(&mut self) -> io::Result<Action> {
+        let usage = limits()?.usage();
+        self.apply_usage(usage)
+    }
+
+    fn apply_usage(&mut self, usage: Option<f64>) -> io::Result<Action> {
+        let narenas = arenas::narenas()?;
+        self.apply_usage_to(usage, 0..narenas)
+    }
+
+    // applies the policy, only changing the decay times of the specified arenas
+    fn apply_usage_to(&mut self, usage: Option<f64>, arenas: Range<c_uint>) -> io::Result<Action> {
+        let decay = match usage {
+            Some(usage) => exceeds(self.decay_threshold, usage),
+            None => false,
+        };
+
+        let action = if decay {
+            self.lower_decay(arenas)?;
+            Action::Decay
+        } else if self.saved.is_some() {
+            self.restore_decay(arenas)?;
+            Action::Restore
+        } else {
+            Action::None
+        };
+
+        match usage {
+            Some(usage) if exceeds(self.purge_threshold, usage) => {
+                arena::purge(arena::ALL)?;
+                Ok(Action::Purge)
+            }
+            _ => Ok(action),
+        }
+    }
+
+    fn lower_decay(&mut self, arenas: Range<c_uint>) -> io::Result<()> {
+        let default = arenas::DirtyDecayMs::new()?;
+        let dirty_decay_ms = arena::DirtyDecayMs::new()?;
+
+        if self.saved.is_none() {
+            let mut saved = vec![];
+            for i in arenas.clone() {
+                saved.push((i, arena_decay_ms(&dirty_decay_ms, i)?));
+            }
+            self.saved = Some(SavedDecay {
+                default: default.get()?,
+                arenas: saved,
+            });
+        }
+
+        if default.get()? != self.decay_ms {
+            default.set(self.decay_ms)?;
+        }
+        for i in arenas {
+            match arena_decay_ms(&dirty_decay_ms, i)? {
+                Some(decay_ms) if decay_ms != self.decay_ms => {
+                    dirty_decay_ms.set(i, self.decay_ms)?
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn restore_decay(&mut self, arenas: Range<c_uint>) -> io::Result<()> {
+        let saved = match self.saved.take() {
+            Some(saved) => saved,
+            None => return Ok(()),
+        };
+
+        let default = arenas::DirtyDecayMs::new()?;
+        let dirty_decay_ms = arena::DirtyDecayMs::new()?;
+
+        if default.get()? != saved.default {
+            default.set(saved.default)?;
+        }
+        for i in arenas {
+            // arenas created while the decay time was lowered started with the default
+            let original = match saved.arenas.iter().find(|a| a.0 == i) {
+                Some(&(_, Some(original))) => original,
+                _ => saved.default,
+            };
+            match arena_decay_ms(&dirty_decay_ms, i)? {
+                Some(decay_ms) if decay_ms != original => dirty_decay_ms.set(i, original)?,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// returns `None` if the arena has not been initialized
+fn arena_decay_ms(
+    dirty_decay_ms: &arena::DirtyDecayMs,
+    arena: c_uint,
+) -> io::Result<Option<isize>> {
+    match dirty_decay_ms.get(arena) {
+        Ok(decay_ms) => Ok(Some(decay_ms)),
+        Err(ref e) if e.raw_os_error() == Some(libc::EFAULT) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn exceeds(threshold: Option<f64>, usage: f64) -> bool {
+    match threshold {
+        Some(threshold) => usage >= threshold,
+        None => false,
+    }
+}
+
+fn read(dir: &Path, file: &str) -> io::Result<String> {
+    fs::read_to_string(dir.join(file))
+}
+
+fn cgroup_dir() -> io::Result<PathBuf> {
+    let cgroup = fs::read_to_string("/proc/self/cgroup")?;
+    let path = cgroup_path(&cgroup).ok_or_else(not_found)?;
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    let (root, mount_point) = cgroup2_mount(&mountinfo).ok_or_else(not_found)?;
+
+    Ok(resolve(path, root, mount_point))
+}
+
+// maps a cgroup path to a directory under the mount point of the hierarchy's root
+fn resolve(path: &str, root: &str, mount_point: &str) -> PathBuf {
+    let path = Path::new(path);
+    let path = path.strip_prefix(root).unwrap_or(path);
+    let path = path.strip_prefix("/").unwrap_or(path);
+    Path::new(mount_point).join(path)
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "cgroup v2 hierarchy not found")
+}
+
+// the unified hierarchy is listed with ID 0 and no controllers, e.g. `0::/foo/bar`
+fn cgroup_path(cgroup: &str) -> Option<&str> {
+    cgroup
+        .lines()
+        .filter_map(|line| line.strip_prefix("0::"))
+        .next()
+}
+
+// returns the root and mount point of the first cgroup2 mount
+fn cgroup2_mount(mountinfo: &str) -> Option<(&str, &str)> {
+    for line in mountinfo.lines() {
+        let mut halves = line.splitn(2, " - ");
+        let fields = halves.next().unwrap_or("");
+        let fs_type = halves.next().and_then(|s| s.split_whitespace().next());
+        if fs_type != Some("cgroup2") {
+            continue;
+        }
+
+        let mut fields = fields.split_whitespace().skip(3);
+        if let (Some(root), Some(mount_point)) = (fields.next(), fields.next()) {
+            return Some((root, mount_point));
+        }
+    }
+
+    None
+}
+
+fn parse_limit(s: &str) -> io::Result<Option<u64>> {
+    if s.trim() == "max" {
+        Ok(None)
+    } else {
+        parse_u64(s).map(Some)
+    }
+}
+
+fn parse_u64(s: &str) -> io::Result<u64> {
+    s.trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use get;
+    use std::os::raw::c_char;
+
+    #[test]
+    fn parse_cgroup() {
+        let cgroup = "4:memory:/foo\n0::/system.slice/foo.service\n";
+        assert_eq!(cgroup_path(cgroup), Some("/system.slice/foo.service"));
+        assert_eq!(cgroup_path("4:memory:/foo\n"), None);
+    }
+
+    #[test]
+    fn parse_mountinfo() {
+        let mountinfo = "\
+24 30 0:22 / /sys rw,nosuid shared:7 - sysfs sysfs rw
+31 24 0:26 / /sys/fs/cgroup/unified rw,nosuid shared:9 - cgroup2 cgroup2 rw,nsdelegate
+";
+        assert_eq!(
+            cgroup2_mount(mountinfo),
+            Some(("/", "/sys/fs/cgroup/unified"))
+        );
+        assert_eq!(
+            cgroup2_mount("24 30 0:22 / /sys rw - sysfs sysfs rw\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn resolve_dir() {
+        assert_eq!(
+            resolve("/system.slice/foo.service", "/", "/sys/fs/cgroup"),
+            Path::new("/sys/fs/cgroup/system.slice/foo.service")
+        );
+        assert_eq!(
+            resolve("/foo/x", "/foo", "/sys/fs/cgroup"),
+            Path::new("/sys/fs/cgroup/x")
+        );
+        assert_eq!(
+            resolve("/foobar/x", "/foo", "/sys/fs/cgroup"),
+            Path::new("/sys/fs/cgroup/foobar/x")
+        );
+        assert_eq!(
+            resolve("/", "/", "/sys/fs/cgroup"),
+            Path::new("/sys/fs/cgroup")
+        );
+    }
+
+    // restores the decay time with which new arenas are initialized
+    struct DefaultDecayGuard(isize);
+
+    impl Drop for DefaultDecayGuard {
+        fn drop(&mut self) {
+            let _ = arenas::set_dirty_decay_ms(self.0);
+        }
+    }
+
+    #[test]
+    fn decay_policy() {
+        const ARENAS_CREATE: *const c_char = b"arenas.create\0" as *const _ as *const _;
+
+        let mut policy = Policy {
+            decay_ms: 1234,
+            purge_threshold: None,
+            ..Policy::default()
+        };
+        let original_default = arenas::dirty_decay_ms().unwrap();
+        let _guard = DefaultDecayGuard(original_default);
+        // use a dedicated arena so that no other test's arenas are affected
+        let created = unsafe { get::<c_uint>(ARENAS_CREATE).unwrap() };
+        arena::set_dirty_decay_ms(created, 5678).unwrap();
+        let range = created..created + 1;
+
+        assert_eq!(
+            policy.apply_usage_to(Some(0.5), range.clone()).unwrap(),
+            Action::None
+        );
+        assert_eq!(
+            policy.apply_usage_to(None, range.clone()).unwrap(),
+            Action::None
+        );
+
+        assert_eq!(
+            policy.apply_usage_to(Some(0.85), range.clone()).unwrap(),
+            Action::Decay
+        );
+        assert_eq!(arena::dirty_decay_ms(created).unwrap(), 1234);
+        assert_eq!(arenas::dirty_decay_ms().unwrap(), 1234);
+        assert_eq!(
+            policy.apply_usage_to(Some(0.95), range.clone()).unwrap(),
+            Action::Decay
+        );
+
+        assert_eq!(
+            policy.apply_usage_to(Some(0.5), range.clone()).unwrap(),
+            Action::Restore
+        );
+        assert_eq!(arena::dirty_decay_ms(created).unwrap(), 5678);
+        assert_eq!(arenas::dirty_decay_ms().unwrap(), original_default);
+        assert_eq!(
+            policy.apply_usage_to(Some(0.5), range.clone()).unwrap(),
+            Action::None
+        );
+
+        policy.decay_threshold = None;
+        policy.purge_threshold = Some(0.9);
+        assert_eq!(
+            policy.apply_usage_to(Some(0.95), range).unwrap(),
+            Action::Purge
+        );
+        assert_eq!(arena::dirty_decay_ms(created).unwrap(), 5678);
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(parse_limit("max\n").unwrap(), None);
+        assert_eq!(parse_limit("1048576\n").unwrap(), Some(1048576));
+        assert!(parse_limit("bogus").is_err());
+
+        let limits = Limits {
+            max: Some(100),
+            high: Some(80),
+            current: 60,
+        };
+        assert_eq!(limits.limit(), Some(80));
+        assert_eq!(limits.headroom(), Some(20));
+        assert_eq!(limits.usage(), Some(0.75));
+    }
+}
@@ -2,7 +2,7 @@
 use std::io;
 use std::os::raw::c_char;
 
-use {get_str, get_str_mib, name_to_mib};
+use {get, get_mib, get_str, get_str_mib, name_to_mib};
 
 const MALLOC_CONF: *const c_char = b"config.malloc_conf\0" as *const _ as *const _;
 
@@ -66,3 +66,68 @@ impl MallocConf {
         unsafe { get_str_mib(&self.0) }
     }
 }
+
+const PROF: *const c_char = b"config.prof\0" as *const _ as *const _;
+
+/// Determines if jemalloc was built with heap profiling support.
+///
+/// Profiling support is enabled by the `--enable-prof` build configuration option, which
+/// `jemalloc-sys` passes when its `profiling` Cargo feature is enabled.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     println!("profiling support: {}", jemalloc_ctl::config::prof().unwrap());
+/// }
+/// ```
+pub fn prof() -> io::Result<bool> {
+    unsafe { get(PROF) }
+}
+
+/// A type determining if jemalloc was built with heap profiling support.
+///
+/// Profiling support is enabled by the `--enable-prof` build configuration option, which
+/// `jemalloc-sys` passes when its `profiling` Cargo feature is enabled.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::config::Prof;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let prof = Prof::new().unwrap();
+///
+///     println!("profiling support: {}", prof.get().unwrap());
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct Prof([usize; 2]);
+
+impl Prof {
+    /// Returns a new `Prof`.
+    pub fn new() -> io::Result<Prof> {
+        unsafe {
+            let mut mib = [0; 2];
+            name_to_mib(PROF, &mut mib)?;
+            Ok(Prof(mib))
+        }
+    }
+
+    /// Returns the profiling support configuration.
+    pub fn get(&self) -> io::Result<bool> {
+        unsafe { get_mib(&self.0) }
+    }
+}
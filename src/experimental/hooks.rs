@@ -0,0 +1,416 @@
+//! Allocation event hooks.
+//!
+//! Hooks are notified of every allocation, deallocation, and in-place expansion made through
+//! jemalloc's API by any thread. jemalloc supports at most `HOOK_MAX` (4, see
+//! `include/jemalloc/internal/hook.h`) sets of hooks at once; installing more fails with the
+//! `EAGAIN` OS error code.
+//!
+//! Installed `Hooks` values are leaked, since jemalloc cannot report when callbacks which started
+//! before the hooks were removed have finished. Hooks are intended to be installed a bounded number
+//! of times over the life of a process.
+//!
+//! This module requires the `hooks` Cargo feature.
+use jemalloc_sys;
+use libc::{c_int, c_void};
+use std::cell::Cell;
+use std::io;
+use std::mem;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::ptr;
+
+use cvt;
+
+const INSTALL: *const c_char = b"experimental.hooks.install\0" as *const _ as *const _;
+const REMOVE: *const c_char = b"experimental.hooks.remove\0" as *const _ as *const _;
+
+/// The function through which memory was allocated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AllocKind {
+    /// `malloc`.
+    Malloc,
+    /// `posix_memalign`.
+    PosixMemalign,
+    /// `aligned_alloc`.
+    AlignedAlloc,
+    /// `calloc`.
+    Calloc,
+    /// `memalign`.
+    Memalign,
+    /// `valloc`.
+    Valloc,
+    /// `mallocx`.
+    Mallocx,
+    /// `realloc`, when the allocation was moved.
+    Realloc,
+    /// `rallocx`, when the allocation was moved.
+    Rallocx,
+}
+
+/// The function through which memory was deallocated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DallocKind {
+    /// `free`.
+    Free,
+    /// `dallocx`.
+    Dallocx,
+    /// `sdallocx`.
+    Sdallocx,
+    /// `realloc`, when the allocation was moved.
+    Realloc,
+    /// `rallocx`, when the allocation was moved.
+    Rallocx,
+}
+
+/// The function through which an allocation was resized in place.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExpandKind {
+    /// `realloc`.
+    Realloc,
+    /// `rallocx`.
+    Rallocx,
+    /// `xallocx`.
+    Xallocx,
+}
+
+/// Callbacks for allocation events.
+///
+/// Callbacks run inside the allocator, on the thread making the allocation, so they should be
+/// fast. Allocations made by a callback do not trigger further callbacks. A panic in a callback
+/// aborts the process, since it cannot unwind through jemalloc.
+pub trait Hooks: Sync + Send + 'static {
+    /// Called after memory is allocated.
+    ///
+    /// `size` is the requested size of the allocation.
+    fn on_alloc(&self, kind: AllocKind, ptr: *mut u8, size: usize) {
+        let _ = (kind, ptr, size);
+    }
+
+    /// Called before memory is deallocated.
+    fn on_dalloc(&self, kind: DallocKind, ptr: *mut u8) {
+        let _ = (kind, ptr);
+    }
+
+    /// Called after an allocation is resized in place.
+    fn on_expand(&self, kind: ExpandKind, ptr: *mut u8, old_usize: usize, new_usize: usize) {
+        let _ = (kind, ptr, old_usize, new_usize);
+    }
+}
+
+type AllocHook = unsafe extern "C" fn(*mut c_void, c_int, *mut c_void, usize, *mut usize);
+type DallocHook = unsafe extern "C" fn(*mut c_void, c_int, *mut c_void, *mut usize);
+type ExpandHook =
+    unsafe extern "C" fn(*mut c_void, c_int, *mut c_void, usize, usize, usize, *mut usize);
+
+// hooks_t
+#[repr(C)]
+struct RawHooks {
+    alloc_hook: Option<AllocHook>,
+    dalloc_hook: Option<DallocHook>,
+    expand_hook: Option<ExpandHook>,
+    extra: *mut c_void,
+}
+
+/// A guard which removes installed hooks when dropped.
+///
+/// Callbacks may still be running on other threads when the hooks are removed, so the `Hooks`
+/// value itself is leaked rather than dropped.
+pub struct HooksGuard {
+    handle: *mut c_void,
+}
+
+unsafe impl Send for HooksGuard {}
+unsafe impl Sync for HooksGuard {}
+
+impl Drop for HooksGuard {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = cvt(jemalloc_sys::mallctl(
+                REMOVE,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                &mut self.handle as *mut _ as *mut _,
+                mem::size_of::<*mut c_void>(),
+            ));
+        }
+    }
+}
+
+/// Installs allocation hooks.
+///
+/// The hooks remain installed until the returned guard is dropped. jemalloc copies its hook table,
+/// but `hooks` is moved to the heap and leaked, even after the guard is dropped, unless
+/// installation fails.
+///
+/// This corresponds to `experimental.hooks.install` and `experimental.hooks.remove` in jemalloc's
+/// API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::experimental::hooks::{self, AllocKind, Hooks};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// struct Counter(AtomicUsize);
+///
+/// impl Hooks for Counter {
+///     fn on_alloc(&self, _: AllocKind, _: *mut u8, size: usize) {
+///         self.0.fetch_add(size, Ordering::Relaxed);
+///     }
+/// }
+///
+/// fn main() {
+///     let _guard = hooks::install(Counter(AtomicUsize::new(0))).unwrap();
+///     let _buf = vec![0u8; 1024];
+/// }
+/// ```
+pub fn install<H>(hooks: H) -> io::Result<HooksGuard>
+where
+    H: Hooks,
+{
+    let extra = Box::into_raw(Box::new(hooks));
+    let mut raw = RawHooks {
+        alloc_hook: Some(alloc_hook::<H>),
+        dalloc_hook: Some(dalloc_hook::<H>),
+        expand_hook: Some(expand_hook::<H>),
+        extra: extra as *mut c_void,
+    };
+
+    let mut handle = ptr::null_mut::<c_void>();
+    let mut len = mem::size_of::<*mut c_void>();
+    let r = unsafe {
+        cvt(jemalloc_sys::mallctl(
+            INSTALL,
+            &mut handle as *mut _ as *mut _,
+            &mut len,
+            &mut raw as *mut _ as *mut _,
+            mem::size_of::<RawHooks>(),
+        ))
+    };
+    if let Err(e) = r {
+        unsafe {
+            drop(Box::from_raw(extra));
+        }
+        return Err(e);
+    }
+
+    Ok(HooksGuard { handle })
+}
+
+thread_local! {
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+// runs a callback unless one is already running on this thread
+fn guarded<F>(f: F)
+where
+    F: FnOnce(),
+{
+    let _ = IN_HOOK.try_with(|in_hook| {
+        if in_hook.replace(true) {
+            return;
+        }
+        let r = panic::catch_unwind(AssertUnwindSafe(f));
+        in_hook.set(false);
+        if r.is_err() {
+            process::abort();
+        }
+    });
+}
+
+unsafe extern "C" fn alloc_hook<H>(
+    extra: *mut c_void,
+    kind: c_int,
+    result: *mut c_void,
+    _: usize,
+    args: *mut usize,
+) where
+    H: Hooks,
+{
+    // hook_alloc_t
+    let (kind, size) = match kind {
+        0 => (AllocKind::Malloc, *args),
+        1 => (AllocKind::PosixMemalign, *args.add(2)),
+        2 => (AllocKind::AlignedAlloc, *args.add(1)),
+        3 => (AllocKind::Calloc, (*args).wrapping_mul(*args.add(1))),
+        4 => (AllocKind::Memalign, *args.add(1)),
+        5 => (AllocKind::Valloc, *args),
+        6 => (AllocKind::Mallocx, *args),
+        7 => (AllocKind::Realloc, *args.add(1)),
+        8 => (AllocKind::Rallocx, *args.add(1)),
+        _ => return,
+    };
+    let hooks = &*(extra as *const H);
+    guarded(|| hooks.on_alloc(kind, result as *mut u8, size));
+}
+
+unsafe extern "C" fn dalloc_hook<H>(
+    extra: *mut c_void,
+    kind: c_int,
+    address: *mut c_void,
+    _: *mut usize,
+) where
+    H: Hooks,
+{
+    // hook_dalloc_t
+    let kind = match kind {
+        0 => DallocKind::Free,
+        1 => DallocKind::Dallocx,
+        2 => DallocKind::Sdallocx,
+        3 => DallocKind::Realloc,
+        4 => DallocKind::Rallocx,
+        _ => return,
+    };
+    let hooks = &*(extra as *const H);
+    guarded(|| hooks.on_dalloc(kind, address as *mut u8));
+}
+
+unsafe extern "C" fn expand_hook<H>(
+    extra: *mut c_void,
+    kind: c_int,
+    address: *mut c_void,
+    old_usize: usize,
+    new_usize: usize,
+    _: usize,
+    _: *mut usize,
+) where
+    H: Hooks,
+{
+    // hook_expand_t
+    let kind = match kind {
+        0 => ExpandKind::Realloc,
+        1 => ExpandKind::Rallocx,
+        2 => ExpandKind::Xallocx,
+        _ => return,
+    };
+    let hooks = &*(extra as *const H);
+    guarded(|| hooks.on_expand(kind, address as *mut u8, old_usize, new_usize));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use version_at_least;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl Hooks for Recorder {
+        fn on_alloc(&self, kind: AllocKind, _: *mut u8, size: usize) {
+            // allocates, which must not recurse
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("alloc {:?} {}", kind, size));
+            unsafe {
+                let mut args = [1, 0, 0];
+                alloc_hook::<Recorder>(
+                    self as *const _ as *mut c_void,
+                    0,
+                    ptr::null_mut(),
+                    0,
+                    args.as_mut_ptr(),
+                );
+            }
+        }
+
+        fn on_dalloc(&self, kind: DallocKind, _: *mut u8) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("dalloc {:?}", kind));
+        }
+
+        fn on_expand(&self, kind: ExpandKind, _: *mut u8, old_usize: usize, new_usize: usize) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("expand {:?} {} {}", kind, old_usize, new_usize));
+        }
+    }
+
+    #[test]
+    fn trampolines() {
+        let recorder = Recorder::default();
+        let extra = &recorder as *const _ as *mut c_void;
+
+        unsafe {
+            let mut args = [4, 8, 0];
+            alloc_hook::<Recorder>(extra, 3, ptr::null_mut(), 0, args.as_mut_ptr());
+            let mut args = [0, 64, 100];
+            alloc_hook::<Recorder>(extra, 1, ptr::null_mut(), 0, args.as_mut_ptr());
+            alloc_hook::<Recorder>(extra, 100, ptr::null_mut(), 0, args.as_mut_ptr());
+            dalloc_hook::<Recorder>(extra, 2, ptr::null_mut(), args.as_mut_ptr());
+            let mut args = [0, 0, 0, 0];
+            expand_hook::<Recorder>(extra, 2, ptr::null_mut(), 16, 32, 0, args.as_mut_ptr());
+        }
+
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            [
+                "alloc Calloc 32",
+                "alloc PosixMemalign 100",
+                "dalloc Sdallocx",
+                "expand Xallocx 16 32",
+            ]
+        );
+    }
+
+    #[test]
+    fn install_and_remove() {
+        const SIZE: usize = 12345;
+
+        #[derive(Default)]
+        struct Tracker {
+            allocated: AtomicUsize,
+            deallocated: AtomicUsize,
+        }
+
+        impl Hooks for &'static Tracker {
+            fn on_alloc(&self, _: AllocKind, ptr: *mut u8, size: usize) {
+                if size == SIZE {
+                    self.allocated.store(ptr as usize, Ordering::SeqCst);
+                }
+            }
+
+            fn on_dalloc(&self, _: DallocKind, ptr: *mut u8) {
+                if ptr as usize == self.allocated.load(Ordering::SeqCst) {
+                    self.deallocated.store(ptr as usize, Ordering::SeqCst);
+                }
+            }
+        }
+
+        let tracker: &'static Tracker = Box::leak(Box::default());
+
+        // hooks were added in jemalloc 5.2.0
+        if !version_at_least(5, 2, 0) {
+            let err = install(tracker).err().unwrap();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+            return;
+        }
+
+        let guard = install(tracker).unwrap();
+        let buf = vec![0u8; SIZE];
+        let address = buf.as_ptr() as usize;
+        assert_eq!(tracker.allocated.load(Ordering::SeqCst), address);
+        drop(buf);
+        assert_eq!(tracker.deallocated.load(Ordering::SeqCst), address);
+        drop(guard);
+
+        tracker.allocated.store(0, Ordering::SeqCst);
+        drop(vec![0u8; SIZE]);
+        assert_eq!(tracker.allocated.load(Ordering::SeqCst), 0);
+    }
+}
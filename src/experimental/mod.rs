@@ -0,0 +1,10 @@
+//! Experimental jemalloc APIs.
+//!
+//! These correspond to the `experimental.*` namespace in jemalloc's API, which may change or be
+//! removed between jemalloc releases. None of them are available before jemalloc 5.2.0; older
+//! versions return an error with the `ENOENT` OS error code.
+
+#[cfg(feature = "hooks")]
+pub mod hooks;
+pub mod prof_recent;
+pub mod utilization;
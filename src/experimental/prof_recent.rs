@@ -0,0 +1,669 @@
+//! Records of recent sampled allocations.
+//!
+//! When profiling is enabled, jemalloc keeps the most recent sampled allocations, along with the
+//! backtraces of their allocation and deallocation, in a bounded buffer. Dumping that buffer is
+//! much cheaper than a full heap profile, and shows what was allocated around a spike in usage
+//! even if the memory has since been freed.
+use libc::{c_char, c_void};
+use std::any::Any;
+use std::ffi::CStr;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::str;
+
+use {get, get_mib, name_to_mib, set, set_mib};
+
+const ALLOC_MAX: *const c_char = b"experimental.prof_recent.alloc_max\0" as *const _ as *const _;
+const ALLOC_DUMP: *const c_char = b"experimental.prof_recent.alloc_dump\0" as *const _ as *const _;
+
+/// Returns the maximum number of recent allocations which are recorded.
+///
+/// A value of -1 means there is no limit.
+///
+/// This corresponds to `experimental.prof_recent.alloc_max` in jemalloc's API. It was added in
+/// jemalloc 5.3.0; an error with the `ENOENT` OS error code is returned by older versions, or if
+/// profiling is disabled.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let max = jemalloc_ctl::experimental::prof_recent::alloc_max().unwrap();
+///     println!("recording up to {} allocations", max);
+/// }
+/// ```
+pub fn alloc_max() -> io::Result<isize> {
+    unsafe { get(ALLOC_MAX) }
+}
+
+/// Sets the maximum number of recent allocations which are recorded.
+///
+/// A value of -1 means there is no limit, and 0 disables recording.
+///
+/// This corresponds to `experimental.prof_recent.alloc_max` in jemalloc's API. It was added in
+/// jemalloc 5.3.0; an error with the `ENOENT` OS error code is returned by older versions, or if
+/// profiling is disabled.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::experimental::prof_recent::set_alloc_max(1000).unwrap();
+/// }
+/// ```
+pub fn set_alloc_max(alloc_max: isize) -> io::Result<()> {
+    unsafe { set(ALLOC_MAX, alloc_max) }
+}
+
+/// A type providing access to the maximum number of recent allocations which are recorded.
+///
+/// This corresponds to `experimental.prof_recent.alloc_max` in jemalloc's API. It was added in
+/// jemalloc 5.3.0; an error with the `ENOENT` OS error code is returned by older versions, or if
+/// profiling is disabled.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::experimental::prof_recent::AllocMax;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let alloc_max = AllocMax::new().unwrap();
+///     alloc_max.set(1000).unwrap();
+///     assert_eq!(alloc_max.get().unwrap(), 1000);
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct AllocMax([usize; 3]);
+
+impl AllocMax {
+    /// Returns a new `AllocMax`.
+    pub fn new() -> io::Result<AllocMax> {
+        let mut mib = [0; 3];
+        unsafe {
+            name_to_mib(ALLOC_MAX, &mut mib)?;
+        }
+        Ok(AllocMax(mib))
+    }
+
+    /// Returns the maximum number of recent allocations which are recorded.
+    pub fn get(&self) -> io::Result<isize> {
+        unsafe { get_mib(&self.0) }
+    }
+
+    /// Sets the maximum number of recent allocations which are recorded.
+    pub fn set(&self, alloc_max: isize) -> io::Result<()> {
+        unsafe { set_mib(&self.0, alloc_max) }
+    }
+}
+
+/// The allocation or deallocation of a recorded allocation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// The jemalloc-assigned identifier of the thread.
+    pub thread_uid: u64,
+
+    /// The name of the thread, if one was set (see [`thread::set_prof_name`]).
+    ///
+    /// [`thread::set_prof_name`]: ../../thread/fn.set_prof_name.html
+    pub thread_name: Option<String>,
+
+    /// The time of the event in nanoseconds, relative to an unspecified epoch.
+    pub time_ns: u64,
+
+    /// The return addresses of the backtrace of the event, innermost frame first.
+    pub addresses: Vec<u64>,
+}
+
+/// A recorded allocation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecentAlloc {
+    /// The requested size of the allocation.
+    pub size: u64,
+
+    /// The usable size of the allocation.
+    pub usize: u64,
+
+    /// Whether the allocation has been freed.
+    pub released: bool,
+
+    /// The allocation of the memory.
+    pub alloc: Event,
+
+    /// The deallocation of the memory.
+    ///
+    /// This is `None` if the allocation is live, and may be `None` for a released allocation if
+    /// its deallocation was not recorded.
+    pub dalloc: Option<Event>,
+}
+
+/// The recorded recent allocations.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let recent = jemalloc_ctl::experimental::prof_recent::alloc_dump().unwrap();
+///     for alloc in recent.allocs.iter().filter(|a| !a.released) {
+///         println!("{} bytes live from {:x?}", alloc.size, alloc.alloc.addresses);
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecentAllocs {
+    /// The average number of bytes allocated between samples.
+    pub sample_interval: u64,
+
+    /// The maximum number of recent allocations which are recorded.
+    pub alloc_max: isize,
+
+    /// The recorded allocations, oldest first.
+    pub allocs: Vec<RecentAlloc>,
+}
+
+impl RecentAllocs {
+    /// Parses the JSON output of `experimental.prof_recent.alloc_dump`.
+    pub fn parse(buf: &[u8]) -> io::Result<RecentAllocs> {
+        let s = str::from_utf8(buf).map_err(|_| error("invalid UTF-8"))?;
+        let mut parser = Parser { s, pos: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != s.len() {
+            return Err(error("trailing characters"));
+        }
+
+        let allocs = value
+            .field("recent_alloc")?
+            .array()?
+            .iter()
+            .map(recent_alloc)
+            .collect::<io::Result<_>>()?;
+
+        Ok(RecentAllocs {
+            sample_interval: value.field("sample_interval")?.number()?,
+            alloc_max: value.field("recent_alloc_max")?.number()?,
+            allocs,
+        })
+    }
+}
+
+fn recent_alloc(value: &Value) -> io::Result<RecentAlloc> {
+    let dalloc = match value.get("dalloc_thread_uid") {
+        Some(_) => Some(event(value, "dalloc")?),
+        None => None,
+    };
+
+    Ok(RecentAlloc {
+        size: value.field("size")?.number()?,
+        usize: value.field("usize")?.number()?,
+        released: value.field("released")?.bool()?,
+        alloc: event(value, "alloc")?,
+        dalloc,
+    })
+}
+
+fn event(value: &Value, prefix: &str) -> io::Result<Event> {
+    let field = |name: &str| value.field(&format!("{}_{}", prefix, name));
+
+    let thread_name = match value.get(&format!("{}_thread_name", prefix)) {
+        Some(name) => Some(name.string()?.to_string()),
+        None => None,
+    };
+    let addresses = field("trace")?
+        .array()?
+        .iter()
+        .map(|address| {
+            let address = address.string()?;
+            let digits = address.strip_prefix("0x").unwrap_or(address);
+            u64::from_str_radix(digits, 16).map_err(|_| error("invalid address"))
+        })
+        .collect::<io::Result<_>>()?;
+
+    Ok(Event {
+        thread_uid: field("thread_uid")?.number()?,
+        thread_name,
+        time_ns: field("time")?.number()?,
+        addresses,
+    })
+}
+
+#[repr(C)]
+struct WriteCb {
+    write_cb: extern "C" fn(*mut c_void, *const c_char),
+    cbopaque: *mut c_void,
+}
+
+struct State<W> {
+    writer: W,
+    error: io::Result<()>,
+    panic: Result<(), Box<dyn Any + Send>>,
+}
+
+extern "C" fn callback<W>(opaque: *mut c_void, buf: *const c_char)
+where
+    W: Write,
+{
+    unsafe {
+        let state = &mut *(opaque as *mut State<W>);
+        if state.error.is_err() || state.panic.is_err() {
+            return;
+        }
+
+        let buf = CStr::from_ptr(buf);
+        match panic::catch_unwind(AssertUnwindSafe(|| state.writer.write_all(buf.to_bytes()))) {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => state.error = Err(e),
+            Err(e) => state.panic = Err(e),
+        }
+    }
+}
+
+/// Writes the recorded recent allocations as JSON.
+///
+/// This corresponds to `experimental.prof_recent.alloc_dump` in jemalloc's API. It was added in
+/// jemalloc 5.3.0; an error with the `ENOENT` OS error code is returned by older versions, or if
+/// profiling is disabled.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let stdout = std::io::stdout();
+///     jemalloc_ctl::experimental::prof_recent::alloc_dump_to_writer(stdout.lock()).unwrap();
+/// }
+/// ```
+pub fn alloc_dump_to_writer<W>(writer: W) -> io::Result<()>
+where
+    W: Write,
+{
+    unsafe {
+        let mut state = State {
+            writer,
+            error: Ok(()),
+            panic: Ok(()),
+        };
+        let write_cb = WriteCb {
+            write_cb: callback::<W>,
+            cbopaque: &mut state as *mut _ as *mut c_void,
+        };
+        set(ALLOC_DUMP, write_cb)?;
+        if let Err(e) = state.panic {
+            panic::resume_unwind(e);
+        }
+        state.error
+    }
+}
+
+/// Returns the recorded recent allocations.
+///
+/// This corresponds to `experimental.prof_recent.alloc_dump` in jemalloc's API. It was added in
+/// jemalloc 5.3.0; an error with the `ENOENT` OS error code is returned by older versions, or if
+/// profiling is disabled.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let recent = jemalloc_ctl::experimental::prof_recent::alloc_dump().unwrap();
+///     println!("{} recent allocations", recent.allocs.len());
+/// }
+/// ```
+pub fn alloc_dump() -> io::Result<RecentAllocs> {
+    let mut buf = vec![];
+    alloc_dump_to_writer(&mut buf)?;
+    RecentAllocs::parse(&buf)
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// a minimal JSON document model, sufficient for jemalloc's output
+enum Value<'a> {
+    Null,
+    Bool(bool),
+    Number(&'a str),
+    String(String),
+    Array(Vec<Value<'a>>),
+    Object(Vec<(String, Value<'a>)>),
+}
+
+impl<'a> Value<'a> {
+    fn get(&self, name: &str) -> Option<&Value<'a>> {
+        match *self {
+            Value::Object(ref fields) => fields.iter().find(|f| f.0 == name).map(|f| &f.1),
+            _ => None,
+        }
+    }
+
+    fn field(&self, name: &str) -> io::Result<&Value<'a>> {
+        self.get(name)
+            .ok_or_else(|| error(&format!("missing field `{}`", name)))
+    }
+
+    fn number<T>(&self) -> io::Result<T>
+    where
+        T: str::FromStr,
+    {
+        match *self {
+            Value::Number(n) => n.parse().map_err(|_| error("invalid number")),
+            _ => Err(error("expected a number")),
+        }
+    }
+
+    fn bool(&self) -> io::Result<bool> {
+        match *self {
+            Value::Bool(b) => Ok(b),
+            _ => Err(error("expected a boolean")),
+        }
+    }
+
+    fn string(&self) -> io::Result<&str> {
+        match *self {
+            Value::String(ref s) => Ok(s),
+            _ => Err(error("expected a string")),
+        }
+    }
+
+    fn array(&self) -> io::Result<&[Value<'a>]> {
+        match *self {
+            Value::Array(ref values) => Ok(values),
+            Value::Null => Ok(&[]),
+            _ => Err(error("expected an array")),
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.s[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.s.as_bytes().get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> io::Result<()> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(error(&format!("expected `{}`", c as char)))
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Value<'a>) -> io::Result<Value<'a>> {
+        if self.s[self.pos..].starts_with(keyword) {
+            self.pos += keyword.len();
+            Ok(value)
+        } else {
+            Err(error("invalid value"))
+        }
+    }
+
+    fn value(&mut self) -> io::Result<Value<'a>> {
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'n') => self.keyword("null", Value::Null),
+            Some(b't') => self.keyword("true", Value::Bool(true)),
+            Some(b'f') => self.keyword("false", Value::Bool(false)),
+            Some(b'-') | Some(b'0'..=b'9') => {
+                let start = self.pos;
+                let len = self.s[start..]
+                    .find(|c: char| !matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E'))
+                    .unwrap_or(self.s.len() - start);
+                self.pos += len;
+                Ok(Value::Number(&self.s[start..self.pos]))
+            }
+            _ => Err(error("invalid value")),
+        }
+    }
+
+    fn object(&mut self) -> io::Result<Value<'a>> {
+        self.expect(b'{')?;
+        let mut fields = vec![];
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(error("expected a field name"));
+            }
+            let name = self.string()?;
+            self.expect(b':')?;
+            fields.push((name, self.value()?));
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(fields));
+                }
+                _ => return Err(error("expected `,` or `}`")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> io::Result<Value<'a>> {
+        self.expect(b'[')?;
+        let mut values = vec![];
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(values));
+                }
+                _ => return Err(error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        self.expect(b'"')?;
+        let mut out = String::new();
+        let mut chars = self.s[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(out);
+                }
+                '\\' => {
+                    let c = match chars.next() {
+                        Some((_, 'n')) => '\n',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, 'u')) => {
+                            let mut code = 0;
+                            for _ in 0..4 {
+                                let digit = chars
+                                    .next()
+                                    .and_then(|(_, c)| c.to_digit(16))
+                                    .ok_or_else(|| error("invalid escape"))?;
+                                code = code * 16 + digit;
+                            }
+                            // surrogate pairs are not produced by jemalloc
+                            ::std::char::from_u32(code).unwrap_or('\u{fffd}')
+                        }
+                        Some((_, c @ '"')) | Some((_, c @ '\\')) | Some((_, c @ '/')) => c,
+                        _ => return Err(error("invalid escape")),
+                    };
+                    out.push(c);
+                }
+                c => out.push(c),
+            }
+        }
+        Err(error("unterminated string"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config;
+    use libc;
+    use opt;
+    use version_at_least;
+
+    // the compact JSON emitted by jemalloc 5.3, split for readability
+    const DUMP: &str = concat!(
+        r#"{"sample_interval":524288,"recent_alloc_max":10000,"recent_alloc":["#,
+        r#"{"size":1000,"usize":1024,"released":true,"alloc_thread_uid":0,"#,
+        r#""alloc_thread_name":"main","alloc_time":1712345678,"#,
+        r#""alloc_trace":["0x55d4c3a2b1f0","0x55d4c3a2b3c4"],"dalloc_thread_uid":3,"#,
+        r#""dalloc_thread_name":"worker-1","dalloc_time":1712399999,"#,
+        r#""dalloc_trace":["0x55d4c3a2c000"]},"#,
+        r#"{"size":24,"usize":32,"released":true,"alloc_thread_uid":3,"#,
+        r#""alloc_time":1712399000,"alloc_trace":["0x55d4c3a2d010"]},"#,
+        r#"{"size":40000,"usize":40960,"released":false,"alloc_thread_uid":3,"#,
+        r#""alloc_time":1712400000,"alloc_trace":[]}"#,
+        "]}\n",
+    );
+
+    #[test]
+    fn parse() {
+        let recent = RecentAllocs::parse(DUMP.as_bytes()).unwrap();
+        assert_eq!(recent.sample_interval, 524288);
+        assert_eq!(recent.alloc_max, 10000);
+        assert_eq!(
+            recent.allocs,
+            [
+                RecentAlloc {
+                    size: 1000,
+                    usize: 1024,
+                    released: true,
+                    alloc: Event {
+                        thread_uid: 0,
+                        thread_name: Some("main".to_string()),
+                        time_ns: 1712345678,
+                        addresses: vec![0x55d4c3a2b1f0, 0x55d4c3a2b3c4],
+                    },
+                    dalloc: Some(Event {
+                        thread_uid: 3,
+                        thread_name: Some("worker-1".to_string()),
+                        time_ns: 1712399999,
+                        addresses: vec![0x55d4c3a2c000],
+                    }),
+                },
+                RecentAlloc {
+                    size: 24,
+                    usize: 32,
+                    released: true,
+                    alloc: Event {
+                        thread_uid: 3,
+                        thread_name: None,
+                        time_ns: 1712399000,
+                        addresses: vec![0x55d4c3a2d010],
+                    },
+                    dalloc: None,
+                },
+                RecentAlloc {
+                    size: 40000,
+                    usize: 40960,
+                    released: false,
+                    alloc: Event {
+                        thread_uid: 3,
+                        thread_name: None,
+                        time_ns: 1712400000,
+                        addresses: vec![],
+                    },
+                    dalloc: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(RecentAllocs::parse(b"").is_err());
+        assert!(RecentAllocs::parse(b"{\"sample_interval\": 1}").is_err());
+        assert!(RecentAllocs::parse(&DUMP.as_bytes()[..DUMP.len() - 4]).is_err());
+        assert!(RecentAllocs::parse(format!("{} x", DUMP).as_bytes()).is_err());
+    }
+
+    #[test]
+    fn dump() {
+        // recent allocation records were added in jemalloc 5.3.0
+        if !version_at_least(5, 3, 0) {
+            let err = alloc_dump().err().unwrap();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+            return;
+        }
+        // the records are only kept while heap profiling is enabled
+        if !config::prof().unwrap() || !opt::prof().unwrap() {
+            return;
+        }
+
+        set_alloc_max(1000).unwrap();
+        // far larger than the sampling interval, so it is sampled
+        let buf = vec![0u8; 64 * 1024 * 1024];
+
+        let mut json = vec![];
+        alloc_dump_to_writer(&mut json).unwrap();
+        let recent = RecentAllocs::parse(&json).unwrap();
+        assert!(recent.sample_interval > 0);
+        assert_eq!(recent.alloc_max, 1000);
+        let alloc = recent
+            .allocs
+            .iter()
+            .rev()
+            .find(|a| a.size == buf.len() as u64)
+            .unwrap();
+        assert!(!alloc.released);
+        assert!(alloc.usize >= alloc.size);
+        assert!(!alloc.alloc.addresses.is_empty());
+        assert_eq!(alloc.dalloc, None);
+    }
+}
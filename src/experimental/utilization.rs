@@ -0,0 +1,323 @@
+//! Memory utilization of the extents backing allocations.
+//!
+//! Small allocations are packed into slabs of same-sized regions. A long-lived allocation can keep
+//! an otherwise empty slab alive, so a cache whose entries sit on sparsely used slabs can reduce
+//! fragmentation by reallocating them. These queries report how full the slab of an allocation is
+//! compared to the other slabs of its size class.
+use jemalloc_sys;
+use libc::c_char;
+use std::io;
+use std::mem;
+
+use cvt;
+
+const QUERY: *const c_char = b"experimental.utilization.query\0" as *const _ as *const _;
+const BATCH_QUERY: *const c_char =
+    b"experimental.utilization.batch_query\0" as *const _ as *const _;
+
+/// The utilization of the extent containing an allocation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ExtentUtilization {
+    /// The number of free regions in the extent.
+    ///
+    /// This is always 0 for allocations not in a slab.
+    pub free_regions: usize,
+
+    /// The total number of regions in the extent.
+    ///
+    /// This is always 1 for allocations not in a slab.
+    pub regions: usize,
+
+    /// The size of the extent in bytes.
+    ///
+    /// For allocations not in a slab, this is the usable size of the allocation.
+    pub size: usize,
+}
+
+impl ExtentUtilization {
+    /// Returns the fraction of the extent's regions which are in use.
+    pub fn utilization(&self) -> f64 {
+        if self.regions == 0 {
+            return 0.;
+        }
+        (self.regions - self.free_regions) as f64 / self.regions as f64
+    }
+}
+
+/// The utilization of the slabs of an allocation's size class in its arena.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BinUtilization {
+    /// The number of free regions across the bin's slabs.
+    pub free_regions: usize,
+
+    /// The total number of regions across the bin's slabs.
+    pub regions: usize,
+
+    /// The address of the slab from which the bin is currently allocating.
+    pub current_slab: usize,
+}
+
+impl BinUtilization {
+    /// Returns the fraction of the bin's regions which are in use.
+    pub fn utilization(&self) -> f64 {
+        if self.regions == 0 {
+            return 0.;
+        }
+        (self.regions - self.free_regions) as f64 / self.regions as f64
+    }
+}
+
+/// The utilization of an allocation's extent and bin.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Utilization {
+    /// The utilization of the allocation's extent.
+    pub extent: ExtentUtilization,
+
+    /// The utilization of the allocation's bin.
+    ///
+    /// This is `None` for allocations not in a slab.
+    pub bin: Option<BinUtilization>,
+}
+
+impl Utilization {
+    /// Determines if reallocating the allocation is likely to reduce fragmentation.
+    ///
+    /// This is the case when the allocation's slab is less utilized than its bin as a whole and is
+    /// not the slab the bin is currently allocating from, since a new allocation of the same size
+    /// would most likely be placed in a fuller slab.
+    pub fn is_worth_moving(&self, ptr: *const u8) -> bool {
+        let bin = match self.bin {
+            Some(ref bin) => bin,
+            None => return false,
+        };
+        let slab_start = bin.current_slab;
+        let in_current_slab =
+            slab_start != 0 && (ptr as usize).wrapping_sub(slab_start) < self.extent.size;
+        !in_current_slab && self.extent.utilization() < bin.utilization()
+    }
+}
+
+// the output of experimental.utilization.query
+#[repr(C)]
+struct RawUtilization {
+    nfree: usize,
+    nregs: usize,
+    size: usize,
+    bin_nfree: usize,
+    bin_nregs: usize,
+    slabcur_addr: *mut u8,
+}
+
+// an element of the output of experimental.utilization.batch_query
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct RawExtentUtilization {
+    nfree: usize,
+    nregs: usize,
+    size: usize,
+}
+
+/// Returns the utilization of the extent and bin containing an allocation.
+///
+/// This corresponds to `experimental.utilization.query` in jemalloc's API. It was added in
+/// jemalloc 5.2.0; an error with the `ENOENT` OS error code is returned by older versions.
+///
+/// # Safety
+///
+/// `ptr` must point to the start of a live allocation made by jemalloc.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::experimental::utilization;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let value = Box::new([0u8; 48]);
+///     let ptr = &*value as *const _ as *const u8;
+///     let utilization = unsafe { utilization::query(ptr).unwrap() };
+///     println!("slab is {:.0}% full", utilization.extent.utilization() * 100.);
+///     if utilization.is_worth_moving(ptr) {
+///         let _moved = Box::new(*value);
+///     }
+/// }
+/// ```
+pub unsafe fn query(ptr: *const u8) -> io::Result<Utilization> {
+    let mut raw = mem::MaybeUninit::<RawUtilization>::uninit();
+    let mut len = mem::size_of::<RawUtilization>();
+    let mut ptr = ptr;
+    cvt(jemalloc_sys::mallctl(
+        QUERY,
+        raw.as_mut_ptr() as *mut _,
+        &mut len,
+        &mut ptr as *mut _ as *mut _,
+        mem::size_of::<*const u8>(),
+    ))?;
+    let raw = raw.assume_init();
+
+    let bin = if raw.bin_nregs == 0 {
+        None
+    } else {
+        Some(BinUtilization {
+            free_regions: raw.bin_nfree,
+            regions: raw.bin_nregs,
+            current_slab: raw.slabcur_addr as usize,
+        })
+    };
+
+    Ok(Utilization {
+        extent: ExtentUtilization {
+            free_regions: raw.nfree,
+            regions: raw.nregs,
+            size: raw.size,
+        },
+        bin,
+    })
+}
+
+/// Returns the utilization of the extents containing a batch of allocations.
+///
+/// The results are in the same order as the pointers. Unlike [`query`], bin utilization is not
+/// reported.
+///
+/// This corresponds to `experimental.utilization.batch_query` in jemalloc's API. It was added in
+/// jemalloc 5.2.0; an error with the `ENOENT` OS error code is returned by older versions.
+///
+/// [`query`]: fn.query.html
+///
+/// # Safety
+///
+/// Every pointer must point to the start of a live allocation made by jemalloc.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::experimental::utilization;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let values = (0..16).map(|i| Box::new(i)).collect::<Vec<_>>();
+///     let ptrs = values.iter().map(|v| &**v as *const i32 as *const u8).collect::<Vec<_>>();
+///     for extent in unsafe { utilization::batch_query(&ptrs).unwrap() } {
+///         println!("{}/{} regions free", extent.free_regions, extent.regions);
+///     }
+/// }
+/// ```
+pub unsafe fn batch_query(ptrs: &[*const u8]) -> io::Result<Vec<ExtentUtilization>> {
+    if ptrs.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut raw = vec![
+        RawExtentUtilization {
+            nfree: 0,
+            nregs: 0,
+            size: 0,
+        };
+        ptrs.len()
+    ];
+    let mut len = mem::size_of_val(&raw[..]);
+    cvt(jemalloc_sys::mallctl(
+        BATCH_QUERY,
+        raw.as_mut_ptr() as *mut _,
+        &mut len,
+        ptrs.as_ptr() as *mut _,
+        mem::size_of_val(ptrs),
+    ))?;
+
+    Ok(raw
+        .into_iter()
+        .map(|raw| ExtentUtilization {
+            free_regions: raw.nfree,
+            regions: raw.nregs,
+            size: raw.size,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libc;
+    use std::ptr;
+    use version_at_least;
+
+    #[test]
+    fn worth_moving() {
+        let utilization = |nfree, bin_nfree, current_slab| Utilization {
+            extent: ExtentUtilization {
+                free_regions: nfree,
+                regions: 8,
+                size: 0x1000,
+            },
+            bin: Some(BinUtilization {
+                free_regions: bin_nfree,
+                regions: 64,
+                current_slab,
+            }),
+        };
+
+        assert!(utilization(6, 16, 0x8000).is_worth_moving(0x1010 as *const u8));
+        assert!(!utilization(6, 16, 0x1000).is_worth_moving(0x1010 as *const u8));
+        assert!(!utilization(1, 16, 0x8000).is_worth_moving(0x1010 as *const u8));
+
+        let large = Utilization {
+            extent: ExtentUtilization {
+                free_regions: 0,
+                regions: 1,
+                size: 1 << 20,
+            },
+            bin: None,
+        };
+        assert!(!large.is_worth_moving(ptr::null()));
+    }
+
+    #[test]
+    fn query_allocations() {
+        let small = Box::new([0u8; 48]);
+        let small = &*small as *const _ as *const u8;
+        let large = vec![0u8; 1 << 20];
+        let large = large.as_ptr();
+
+        // the utilization queries were added in jemalloc 5.2.0
+        if !version_at_least(5, 2, 0) {
+            let err = unsafe { query(small) }.err().unwrap();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+            let err = unsafe { batch_query(&[small]) }.err().unwrap();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+            return;
+        }
+
+        unsafe {
+            let utilization = query(small).unwrap();
+            assert!(utilization.extent.free_regions < utilization.extent.regions);
+            assert!(utilization.extent.size >= 48 * utilization.extent.regions);
+            let bin = utilization.bin.unwrap();
+            assert!(bin.free_regions < bin.regions);
+            assert!(bin.regions >= utilization.extent.regions);
+
+            let utilization = query(large).unwrap();
+            assert_eq!(utilization.extent.free_regions, 0);
+            assert_eq!(utilization.extent.regions, 1);
+            assert!(utilization.extent.size >= 1 << 20);
+            assert_eq!(utilization.bin, None);
+
+            let extents = batch_query(&[small, large, small]).unwrap();
+            assert_eq!(extents.len(), 3);
+            // other threads may allocate from the same slab, so only the shape is stable
+            assert_eq!(extents[0].regions, extents[2].regions);
+            assert_eq!(extents[0].size, query(small).unwrap().extent.size);
+            assert_eq!(extents[1], query(large).unwrap().extent);
+        }
+    }
+}
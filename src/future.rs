@@ -0,0 +1,216 @@
+//! Allocation accounting for futures.
+//!
+//! The per-thread counters in the [`thread`] module mix the allocations of every task running on
+//! a thread, and a single task may run on many threads over its lifetime. The [`Measure`] adapter
+//! instead samples the counters of whichever thread is polling it before and after each poll, and
+//! accumulates the difference. It only depends on `std::future`, so it works with any executor.
+//!
+//! [`thread`]: ../thread/index.html
+//! [`Measure`]: struct.Measure.html
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use thread::{AllocatedP, Allocations, DeallocatedP};
+
+/// Wraps a future, measuring the number of bytes allocated and deallocated while it is polled.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use std::future::Future;
+/// use std::pin::Pin;
+/// use std::sync::Arc;
+/// use std::task::{Context, Poll, Wake, Waker};
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// struct Allocate;
+///
+/// impl Future for Allocate {
+///     type Output = usize;
+///
+///     fn poll(self: Pin<&mut Self>, _: &mut Context) -> Poll<usize> {
+///         Poll::Ready(vec![0u8; 1024 * 1024].len())
+///     }
+/// }
+///
+/// struct NoopWaker;
+///
+/// impl Wake for NoopWaker {
+///     fn wake(self: Arc<Self>) {}
+/// }
+///
+/// fn main() {
+///     let mut future = Box::pin(jemalloc_ctl::future::measure(Allocate).unwrap());
+///
+///     let waker = Waker::from(Arc::new(NoopWaker));
+///     let mut cx = Context::from_waker(&waker);
+///     match future.as_mut().poll(&mut cx) {
+///         Poll::Ready((len, allocations)) => {
+///             assert_eq!(len, 1024 * 1024);
+///             assert!(allocations.allocated >= 1024 * 1024);
+///             assert_eq!(allocations.net(), 0);
+///         }
+///         Poll::Pending => unreachable!(),
+///     }
+/// }
+/// ```
+pub fn measure<F>(future: F) -> io::Result<Measure<F>>
+where
+    F: Future,
+{
+    let allocatedp = AllocatedP::new()?;
+    let deallocatedp = DeallocatedP::new()?;
+    // make sure the counters are available so polls don't have to deal with errors
+    allocatedp.get()?;
+    deallocatedp.get()?;
+
+    Ok(Measure {
+        future,
+        allocatedp,
+        deallocatedp,
+        allocations: Allocations::default(),
+    })
+}
+
+/// A future which measures the number of bytes allocated and deallocated while polling another.
+///
+/// It resolves to the inner future's output along with the accumulated byte counts.
+///
+/// Created by the [`measure`] function.
+///
+/// [`measure`]: fn.measure.html
+pub struct Measure<F> {
+    future: F,
+    allocatedp: AllocatedP,
+    deallocatedp: DeallocatedP,
+    allocations: Allocations,
+}
+
+impl<F> Measure<F> {
+    /// Returns the number of bytes allocated and deallocated by the inner future so far.
+    pub fn allocations(&self) -> Allocations {
+        self.allocations
+    }
+}
+
+impl<F> Future for Measure<F>
+where
+    F: Future,
+{
+    type Output = (F::Output, Allocations);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: `future` is structurally pinned, and nothing else is, nor does anything move it.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        // the pointers need to be refetched each time since we may be on a different thread
+        let counters = match (this.allocatedp.get(), this.deallocatedp.get()) {
+            (Ok(allocated), Ok(deallocated)) => {
+                Some((allocated, deallocated, allocated.get(), deallocated.get()))
+            }
+            _ => None,
+        };
+
+        let poll = future.poll(cx);
+
+        if let Some((allocated, deallocated, start_allocated, start_deallocated)) = counters {
+            let allocations = &mut this.allocations;
+            allocations.allocated = allocations
+                .allocated
+                .wrapping_add(allocated.get().wrapping_sub(start_allocated));
+            allocations.deallocated = allocations
+                .deallocated
+                .wrapping_add(deallocated.get().wrapping_sub(start_deallocated));
+        }
+
+        match poll {
+            Poll::Ready(output) => Poll::Ready((output, this.allocations)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::task::{Wake, Waker};
+    use std::thread;
+
+    const MB: usize = 1024 * 1024;
+
+    // allocates on both polls, and frees the first allocation on the second
+    struct TwoPolls {
+        first: Option<Vec<u8>>,
+    }
+
+    impl Future for TwoPolls {
+        type Output = Vec<u8>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Vec<u8>> {
+            match self.first.take() {
+                None => {
+                    self.first = Some(vec![1; MB]);
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+                Some(first) => {
+                    drop(first);
+                    Poll::Ready(vec![2; 2 * MB])
+                }
+            }
+        }
+    }
+
+    struct ChannelWaker(Mutex<Sender<()>>);
+
+    impl Wake for ChannelWaker {
+        fn wake(self: Arc<Self>) {
+            let _ = self.0.lock().unwrap().send(());
+        }
+    }
+
+    #[test]
+    fn polls_on_different_threads() {
+        let (tx, rx) = mpsc::channel();
+        let waker = Waker::from(Arc::new(ChannelWaker(Mutex::new(tx))));
+        let future = Box::pin(measure(TwoPolls { first: None }).unwrap());
+
+        let poll = |mut future: Pin<Box<Measure<TwoPolls>>>, waker: Waker| {
+            thread::spawn(move || {
+                let poll = future.as_mut().poll(&mut Context::from_waker(&waker));
+                // allocations after the poll are not attributed to the future
+                drop(vec![0u8; 16 * MB]);
+                (future, poll)
+            })
+            .join()
+            .unwrap()
+        };
+
+        let (future, first) = poll(future, waker.clone());
+        assert!(first.is_pending());
+        rx.recv().unwrap();
+        let first = future.allocations();
+        assert!(first.allocated >= MB as u64);
+
+        let (_, second) = poll(future, waker);
+        let (buf, allocations) = match second {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("expected the second poll to complete"),
+        };
+        assert_eq!(buf.len(), 2 * MB);
+        assert!(allocations.allocated >= first.allocated + 2 * MB as u64);
+        assert!(allocations.allocated < 16 * MB as u64);
+        assert!(allocations.deallocated >= MB as u64);
+        assert!(allocations.deallocated < 16 * MB as u64);
+    }
+}
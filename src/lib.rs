@@ -71,8 +71,14 @@
 #![doc(html_root_url = "https://docs.rs/jemalloc-ctl/0.1")]
 #![warn(missing_docs)]
 
+#[cfg(feature = "symbolize")]
+extern crate backtrace;
 extern crate jemalloc_sys;
 extern crate libc;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "tracing")]
+extern crate tracing_subscriber;
 
 #[cfg(test)]
 extern crate jemallocator;
@@ -87,12 +93,26 @@ use std::io;
 use std::mem;
 use std::ptr;
 
+pub mod arena;
 pub mod arenas;
+#[cfg(target_os = "linux")]
+pub mod cgroup;
 pub mod config;
+pub mod experimental;
+pub mod future;
 pub mod opt;
+pub mod prof;
+pub mod profile;
+#[cfg(target_os = "linux")]
+pub mod reconcile;
+pub mod size_class;
 pub mod stats;
 pub mod stats_print;
+pub mod tcache;
+pub mod testing;
 pub mod thread;
+#[cfg(feature = "tracing")]
+pub mod trace;
 
 unsafe fn name_to_mib(name: *const c_char, mib: &mut [usize]) -> io::Result<()> {
     let mut len = mib.len();
@@ -169,6 +189,27 @@ unsafe fn set<T>(name: *const c_char, mut value: T) -> io::Result<()> {
     ))
 }
 
+unsafe fn call_mib(mib: &[usize]) -> io::Result<()> {
+    cvt(jemalloc_sys::mallctlbymib(
+        mib.as_ptr(),
+        mib.len(),
+        ptr::null_mut(),
+        ptr::null_mut(),
+        ptr::null_mut(),
+        0,
+    ))
+}
+
+unsafe fn call(name: *const c_char) -> io::Result<()> {
+    cvt(jemalloc_sys::mallctl(
+        name,
+        ptr::null_mut(),
+        ptr::null_mut(),
+        ptr::null_mut(),
+        0,
+    ))
+}
+
 unsafe fn get_set_mib<T>(mib: &[usize], mut value: T) -> io::Result<T> {
     let mut len = mem::size_of::<T>();
     cvt(jemalloc_sys::mallctlbymib(
@@ -196,6 +237,38 @@ unsafe fn get_set<T>(name: *const c_char, mut value: T) -> io::Result<T> {
     Ok(value)
 }
 
+// The minimum alignment jemallocator assumes without passing MALLOCX_ALIGN. This must match
+// jemallocator's table so that we compute the same flags it passes when allocating.
+// Architectures it does not support fall back to 8, for which the flags are merely redundant.
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64",
+    target_arch = "mips64",
+    target_arch = "s390x",
+    target_arch = "sparc64"
+))]
+const MIN_ALIGN: usize = 16;
+#[cfg(not(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64",
+    target_arch = "mips64",
+    target_arch = "s390x",
+    target_arch = "sparc64"
+)))]
+const MIN_ALIGN: usize = 8;
+
+fn layout_to_flags(align: usize, size: usize) -> c_int {
+    if align <= MIN_ALIGN && align <= size {
+        0
+    } else {
+        jemalloc_sys::MALLOCX_ALIGN(align)
+    }
+}
+
 fn cvt(ret: c_int) -> io::Result<()> {
     if ret == 0 {
         Ok(())
@@ -229,6 +302,18 @@ pub fn version() -> io::Result<&'static str> {
     unsafe { get_str(VERSION) }
 }
 
+// determines if the linked jemalloc is at least the specified version, for tests of APIs which
+// were added after the bundled version
+#[cfg(test)]
+fn version_at_least(major: u32, minor: u32, patch: u32) -> bool {
+    let version = version().unwrap();
+    let mut parts = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    let mut next = || parts.next().unwrap_or(0);
+    (next(), next(), next()) >= (major, minor, patch)
+}
+
 /// A type providing access to the jemalloc version string.
 ///
 /// # Example
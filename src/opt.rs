@@ -476,3 +476,77 @@ impl LgTcacheMax {
         unsafe { get_mib(&self.0) }
     }
 }
+
+const PROF: *const c_char = b"opt.prof\0" as *const _ as *const _;
+
+/// Determines if heap profiling is enabled.
+///
+/// This is disabled by default, and can only be enabled if jemalloc was built with profiling
+/// support. An error is returned if it was not. See [`config::prof`] for more information.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     if jemalloc_ctl::config::prof().unwrap() {
+///         println!("heap profiling: {}", jemalloc_ctl::opt::prof().unwrap());
+///     }
+/// }
+/// ```
+///
+/// [`config::prof`]: ../config/fn.prof.html
+pub fn prof() -> io::Result<bool> {
+    unsafe { get(PROF) }
+}
+
+/// A type determining if heap profiling is enabled.
+///
+/// This is disabled by default, and can only be enabled if jemalloc was built with profiling
+/// support. An error is returned if it was not. See [`config::Prof`] for more information.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::config;
+/// use jemalloc_ctl::opt::Prof;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     if config::prof().unwrap() {
+///         let prof = Prof::new().unwrap();
+///
+///         println!("heap profiling: {}", prof.get().unwrap());
+///     }
+/// }
+/// ```
+///
+/// [`config::Prof`]: ../config/struct.Prof.html
+#[derive(Copy, Clone)]
+pub struct Prof([usize; 2]);
+
+impl Prof {
+    /// Returns a new `Prof`.
+    pub fn new() -> io::Result<Prof> {
+        unsafe {
+            let mut mib = [0; 2];
+            name_to_mib(PROF, &mut mib)?;
+            Ok(Prof(mib))
+        }
+    }
+
+    /// Returns the heap profiling behavior.
+    pub fn get(&self) -> io::Result<bool> {
+        unsafe { get_mib(&self.0) }
+    }
+}
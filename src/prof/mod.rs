@@ -0,0 +1,600 @@
+//! Heap profiling operations.
+//!
+//! Heap profiling requires jemalloc to be built with profiling support (see [`config::prof`]),
+//! and enabled at runtime via the `prof` option (see [`opt::prof`]).
+//!
+//! [`config::prof`]: ../config/fn.prof.html
+//! [`opt::prof`]: ../opt/fn.prof.html
+//!
+//! The [`periodic`] module dumps profiles in the background for continuous profiling.
+//!
+//! [`periodic`]: periodic/index.html
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::os::raw::c_char;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use thread::ProfActive;
+use {call, call_mib, config, get, get_mib, name_to_mib, opt, set, set_mib};
+
+pub mod periodic;
+
+const DUMP: *const c_char = b"prof.dump\0" as *const _ as *const _;
+
+/// Dumps a heap profile to the specified file.
+///
+/// This corresponds to `prof.dump` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use std::ffi::CString;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let filename = CString::new("/tmp/heap.prof").unwrap();
+///     jemalloc_ctl::prof::dump(&filename).unwrap();
+/// }
+/// ```
+pub fn dump(filename: &CStr) -> io::Result<()> {
+    unsafe { set(DUMP, filename.as_ptr()) }
+}
+
+/// A type providing the ability to dump heap profiles.
+///
+/// This corresponds to `prof.dump` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::prof::Dump;
+/// use std::ffi::CString;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let dump = Dump::new().unwrap();
+///
+///     let filename = CString::new("/tmp/heap.prof").unwrap();
+///     dump.dump(&filename).unwrap();
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct Dump([usize; 2]);
+
+impl Dump {
+    /// Returns a new `Dump`.
+    pub fn new() -> io::Result<Dump> {
+        let mut mib = [0; 2];
+        unsafe {
+            name_to_mib(DUMP, &mut mib)?;
+        }
+        Ok(Dump(mib))
+    }
+
+    /// Dumps a heap profile to the specified file.
+    pub fn dump(&self, filename: &CStr) -> io::Result<()> {
+        unsafe { set_mib(&self.0, filename.as_ptr()) }
+    }
+}
+
+const ACTIVE: *const c_char = b"prof.active\0" as *const _ as *const _;
+
+/// Returns whether allocations are currently being sampled.
+///
+/// This is initialized by the `prof_active` option, and only has an effect if profiling is
+/// enabled.
+///
+/// This corresponds to `prof.active` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     println!("profiling active: {}", jemalloc_ctl::prof::active().unwrap());
+/// }
+/// ```
+pub fn active() -> io::Result<bool> {
+    unsafe { get(ACTIVE) }
+}
+
+/// Activates or deactivates allocation sampling.
+///
+/// This corresponds to `prof.active` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::prof::set_active(false).unwrap();
+///     assert!(!jemalloc_ctl::prof::active().unwrap());
+/// }
+/// ```
+pub fn set_active(active: bool) -> io::Result<()> {
+    unsafe { set(ACTIVE, active) }
+}
+
+/// A type providing access to whether allocations are currently being sampled.
+///
+/// This corresponds to `prof.active` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::prof::Active;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let active = Active::new().unwrap();
+///     active.set(true).unwrap();
+///     assert!(active.get().unwrap());
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct Active([usize; 2]);
+
+impl Active {
+    /// Returns a new `Active`.
+    pub fn new() -> io::Result<Active> {
+        let mut mib = [0; 2];
+        unsafe {
+            name_to_mib(ACTIVE, &mut mib)?;
+        }
+        Ok(Active(mib))
+    }
+
+    /// Returns whether allocations are currently being sampled.
+    pub fn get(&self) -> io::Result<bool> {
+        unsafe { get_mib(&self.0) }
+    }
+
+    /// Activates or deactivates allocation sampling.
+    pub fn set(&self, active: bool) -> io::Result<()> {
+        unsafe { set_mib(&self.0, active) }
+    }
+}
+
+const RESET: *const c_char = b"prof.reset\0" as *const _ as *const _;
+
+/// Resets all heap profiling statistics, discarding the samples of all current allocations.
+///
+/// This corresponds to `prof.reset` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::prof::reset().unwrap();
+/// }
+/// ```
+pub fn reset() -> io::Result<()> {
+    unsafe { call(RESET) }
+}
+
+/// Resets all heap profiling statistics and sets the sample rate.
+///
+/// The average interval between samples is `2 ^ lg_sample` bytes.
+///
+/// This corresponds to `prof.reset` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     // sample every allocation
+///     jemalloc_ctl::prof::reset_lg_sample(0).unwrap();
+/// }
+/// ```
+pub fn reset_lg_sample(lg_sample: usize) -> io::Result<()> {
+    unsafe { set(RESET, lg_sample) }
+}
+
+/// A type providing the ability to reset heap profiling statistics.
+///
+/// This corresponds to `prof.reset` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::prof::Reset;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let reset = Reset::new().unwrap();
+///     reset.reset().unwrap();
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct Reset([usize; 2]);
+
+impl Reset {
+    /// Returns a new `Reset`.
+    pub fn new() -> io::Result<Reset> {
+        let mut mib = [0; 2];
+        unsafe {
+            name_to_mib(RESET, &mut mib)?;
+        }
+        Ok(Reset(mib))
+    }
+
+    /// Resets all heap profiling statistics.
+    pub fn reset(&self) -> io::Result<()> {
+        unsafe { call_mib(&self.0) }
+    }
+
+    /// Resets all heap profiling statistics and sets the sample rate.
+    pub fn reset_lg_sample(&self, lg_sample: usize) -> io::Result<()> {
+        unsafe { set_mib(&self.0, lg_sample) }
+    }
+}
+
+/// Dumps a heap profile to a writer.
+///
+/// jemalloc can only dump profiles to files, so the profile is written to a private temporary
+/// file, copied to the writer, and then deleted.
+///
+/// An error is returned if jemalloc was built without profiling support or profiling is not
+/// enabled.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use std::io;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let stdout = io::stdout();
+///     jemalloc_ctl::prof::dump_to_writer(stdout.lock()).unwrap();
+/// }
+/// ```
+pub fn dump_to_writer<W>(mut writer: W) -> io::Result<()>
+where
+    W: Write,
+{
+    check_enabled()?;
+
+    let file = TempFile::new()?;
+    dump(&file.filename)?;
+    io::copy(&mut File::open(&file.path)?, &mut writer)?;
+    Ok(())
+}
+
+/// Dumps a heap profile into a buffer.
+///
+/// See [`dump_to_writer`] for more details.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let profile = jemalloc_ctl::prof::dump_to_vec().unwrap();
+///     println!("{}", String::from_utf8_lossy(&profile));
+/// }
+/// ```
+///
+/// [`dump_to_writer`]: fn.dump_to_writer.html
+pub fn dump_to_vec() -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    dump_to_writer(&mut buf)?;
+    Ok(buf)
+}
+
+/// A guard activating allocation sampling process-wide until it is dropped.
+///
+/// The previous value of [`active`] is restored when the guard is dropped.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::prof::ActiveScope;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let scope = ActiveScope::new().unwrap();
+///     let buf = vec![0u8; 1024 * 1024];
+///     drop(scope);
+///
+///     jemalloc_ctl::prof::dump_to_vec().unwrap();
+/// }
+/// ```
+///
+/// [`active`]: fn.active.html
+pub struct ActiveScope {
+    active: Active,
+    previous: bool,
+}
+
+impl ActiveScope {
+    /// Activates allocation sampling, returning a guard which restores the previous state.
+    pub fn new() -> io::Result<ActiveScope> {
+        let active = Active::new()?;
+        let previous = active.get()?;
+        active.set(true)?;
+        Ok(ActiveScope { active, previous })
+    }
+}
+
+impl Drop for ActiveScope {
+    fn drop(&mut self) {
+        let _ = self.active.set(self.previous);
+    }
+}
+
+/// A guard activating sampling of the current thread's allocations until it is dropped.
+///
+/// The previous value of [`thread::prof_active`] is restored when the guard is dropped. Since it
+/// applies to the thread it was created on, it is neither `Sync` nor `Send`.
+///
+/// Allocations are only sampled while [`active`] is also set.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::prof::ThreadActiveScope;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::thread::set_prof_active(false).unwrap();
+///
+///     let scope = ThreadActiveScope::new().unwrap();
+///     let buf = vec![0u8; 1024 * 1024];
+///     drop(scope);
+///
+///     assert!(!jemalloc_ctl::thread::prof_active().unwrap());
+/// }
+/// ```
+///
+/// [`thread::prof_active`]: ../thread/fn.prof_active.html
+/// [`active`]: fn.active.html
+pub struct ThreadActiveScope {
+    active: ProfActive,
+    previous: bool,
+    _p: PhantomData<*const ()>,
+}
+
+impl ThreadActiveScope {
+    /// Activates sampling of the current thread's allocations, returning a guard which restores
+    /// the previous state.
+    pub fn new() -> io::Result<ThreadActiveScope> {
+        let active = ProfActive::new()?;
+        let previous = active.get()?;
+        active.set(true)?;
+        Ok(ThreadActiveScope {
+            active,
+            previous,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl Drop for ThreadActiveScope {
+    fn drop(&mut self) {
+        let _ = self.active.set(self.previous);
+    }
+}
+
+/// Runs a closure with allocation sampling active, returning a heap profile of the whole process.
+///
+/// Profiling statistics are reset before the closure runs, so samples of earlier allocations are
+/// discarded for the whole process. Sampling is then activated process-wide and on the current
+/// thread, and both are restored to their previous state once the closure returns.
+///
+/// `thread.prof.active` can only be changed by the thread it belongs to, so allocations made by
+/// other threads while the closure runs are sampled as well, unless those threads have
+/// deactivated sampling themselves. Unlike [`thread::measure`], the profile therefore describes
+/// the whole process rather than just the closure. Setting the `prof_thread_active_init` option
+/// to `false` leaves sampling inactive on threads which do not opt in.
+///
+/// Heap profiles only include allocations which are still live, so the profile describes the
+/// memory allocated while the closure ran and not yet freed. Enable the `prof_accum` option to
+/// additionally record cumulative counts.
+///
+/// An error is returned if jemalloc was built without profiling support or profiling is not
+/// enabled.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::profile::HeapProfile;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let (buf, dump) = jemalloc_ctl::prof::measure_process(|| vec![0u8; 1024 * 1024]).unwrap();
+///
+///     let profile = HeapProfile::parse(&dump).unwrap();
+///     assert!(profile.total.bytes >= buf.len() as u64);
+/// }
+/// ```
+///
+/// [`thread::measure`]: ../thread/fn.measure.html
+pub fn measure_process<F, T>(f: F) -> io::Result<(T, Vec<u8>)>
+where
+    F: FnOnce() -> T,
+{
+    check_enabled()?;
+
+    let value = {
+        let _thread = ThreadActiveScope::new()?;
+        reset()?;
+        let _process = ActiveScope::new()?;
+        f()
+    };
+
+    Ok((value, dump_to_vec()?))
+}
+
+fn check_enabled() -> io::Result<()> {
+    if !config::prof()? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "jemalloc was built without profiling support",
+        ));
+    }
+
+    if !opt::prof()? {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "heap profiling is not enabled; set `prof:true` in the jemalloc runtime options",
+        ));
+    }
+
+    Ok(())
+}
+
+struct TempFile {
+    path: PathBuf,
+    filename: CString,
+}
+
+impl TempFile {
+    fn new() -> io::Result<TempFile> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        loop {
+            let path = env::temp_dir().join(format!(
+                "jemalloc-ctl-{}-{}.heap",
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            match options.open(&path) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+
+            // construct the guard before anything else can fail so the file is cleaned up
+            let mut file = TempFile {
+                path,
+                filename: CString::default(),
+            };
+            let filename = file.path.to_str().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "non-UTF8 temporary directory")
+            })?;
+            file.filename = CString::new(filename)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            return Ok(file);
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn disabled() {
+        if config::prof().unwrap() && opt::prof().unwrap() {
+            return;
+        }
+
+        let err = dump_to_vec().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn measure_disabled() {
+        if config::prof().unwrap() && opt::prof().unwrap() {
+            return;
+        }
+
+        let err = measure_process(|| ()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn temp_file_cleanup() {
+        let file = TempFile::new().unwrap();
+        let path = file.path.clone();
+        assert!(path.exists());
+        drop(file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,507 @@
+//! Periodic heap profile dumps.
+//!
+//! A [`Profiler`] runs a background thread which dumps heap profiles into a directory at a fixed
+//! interval, when the number of allocated bytes grows past a threshold, or both. Old profiles are
+//! deleted to keep the directory within configurable limits.
+//!
+//! [`Profiler`]: struct.Profiler.html
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use prof::{check_enabled, Active, Dump};
+use stats::Allocated;
+use Epoch;
+
+/// Options controlling a [`Profiler`].
+///
+/// Options are constructed with `Options::default()`, and the fields then set individually.
+///
+/// [`Profiler`]: struct.Profiler.html
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Options {
+    /// The interval between dumps.
+    ///
+    /// Must not be zero. Defaults to 60 seconds.
+    pub interval: Option<Duration>,
+
+    /// The number of bytes by which [`stats::allocated`] must grow relative to its value at the
+    /// previous dump to trigger a dump.
+    ///
+    /// Defaults to `None`.
+    ///
+    /// [`stats::allocated`]: ../../stats/fn.allocated.html
+    pub growth_threshold: Option<usize>,
+
+    /// The interval at which [`stats::allocated`] is checked against the growth threshold.
+    ///
+    /// Must not be zero if a growth threshold is set. Defaults to 1 second.
+    ///
+    /// [`stats::allocated`]: ../../stats/fn.allocated.html
+    pub poll_interval: Duration,
+
+    /// The prefix of profile filenames.
+    ///
+    /// Profiles are named
+    /// `<prefix>.<process ID>.<milliseconds since the Unix epoch>.<sequence number>.heap`. Defaults
+    /// to `jemalloc-ctl`, which is distinct from the `jeprof` prefix of jemalloc's own automatic
+    /// dumps.
+    pub prefix: String,
+
+    /// The maximum number of profiles to retain in the directory.
+    ///
+    /// Defaults to 10.
+    pub max_files: Option<usize>,
+
+    /// The maximum total size in bytes of the profiles retained in the directory.
+    ///
+    /// Defaults to `None`.
+    pub max_bytes: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            interval: Some(Duration::from_secs(60)),
+            growth_threshold: None,
+            poll_interval: Duration::from_secs(1),
+            prefix: "jemalloc-ctl".to_string(),
+            max_files: Some(10),
+            max_bytes: None,
+        }
+    }
+}
+
+struct Shared {
+    shutdown: Mutex<bool>,
+    cvar: Condvar,
+}
+
+/// A background thread dumping heap profiles into a directory.
+///
+/// No profiles are dumped while sampling is inactive (see [`prof::active`]), though the interval
+/// and growth threshold are still reset when one would have been.
+///
+/// Retention limits apply to the profiles written by the current process, with the oldest
+/// profiles deleted first. Profiles written by other processes, including earlier runs of the
+/// same program, are never deleted. The most recent profile is always retained.
+///
+/// The thread is shut down when the `Profiler` is dropped. Use [`shutdown`] to wait for it and
+/// observe errors.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::prof::periodic::{Options, Profiler};
+/// use std::time::Duration;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let mut options = Options::default();
+///     options.interval = Some(Duration::from_secs(5 * 60));
+///     options.growth_threshold = Some(256 * 1024 * 1024);
+///     options.max_bytes = Some(1024 * 1024 * 1024);
+///
+///     let profiler = Profiler::start("/var/lib/my-app/heap", options).unwrap();
+///     // ...
+///     profiler.shutdown().unwrap();
+/// }
+/// ```
+///
+/// [`prof::active`]: ../fn.active.html
+/// [`shutdown`]: #method.shutdown
+pub struct Profiler {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+impl Profiler {
+    /// Starts a profiler writing to the specified directory.
+    ///
+    /// The directory is created if it does not exist. An error is returned if neither an interval
+    /// nor a growth threshold is set, if an interval is zero, or if heap profiling is not enabled.
+    pub fn start<P>(directory: P, options: Options) -> io::Result<Profiler>
+    where
+        P: AsRef<Path>,
+    {
+        validate(&options)?;
+        check_enabled()?;
+
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+
+        let mut state = State {
+            directory,
+            prefix: format!("{}.{}", options.prefix, process::id()),
+            options,
+            epoch: Epoch::new()?,
+            allocated: Allocated::new()?,
+            active: Active::new()?,
+            dump: Dump::new()?,
+            last_dump: Instant::now(),
+            last_allocated: 0,
+            sequence: 0,
+        };
+        if state.options.growth_threshold.is_some() {
+            state.last_allocated = state.allocated()?;
+        }
+
+        let shared = Arc::new(Shared {
+            shutdown: Mutex::new(false),
+            cvar: Condvar::new(),
+        });
+        let thread = thread::Builder::new()
+            .name("jemalloc-ctl-profiler".to_string())
+            .spawn({
+                let shared = shared.clone();
+                move || state.run(&shared)
+            })?;
+
+        Ok(Profiler {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Shuts down the profiler, waiting for the background thread to exit.
+    ///
+    /// If any dump failed, the most recent error is returned.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        *self.shared.shutdown.lock().unwrap() = true;
+        self.shared.cvar.notify_one();
+
+        match self.thread.take() {
+            Some(thread) => match thread.join() {
+                Ok(r) => r,
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "profiler thread panicked",
+                )),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+struct State {
+    directory: PathBuf,
+    // the filename prefix including the process ID, which identifies the profiles to prune
+    prefix: String,
+    options: Options,
+    epoch: Epoch,
+    allocated: Allocated,
+    active: Active,
+    dump: Dump,
+    last_dump: Instant,
+    last_allocated: usize,
+    sequence: u64,
+}
+
+impl State {
+    fn run(&mut self, shared: &Shared) -> io::Result<()> {
+        let mut result = Ok(());
+
+        let mut shutdown = shared.shutdown.lock().unwrap();
+        loop {
+            let timeout = self.timeout();
+            shutdown = shared.cvar.wait_timeout(shutdown, timeout).unwrap().0;
+            if *shutdown {
+                return result;
+            }
+
+            if let Err(e) = self.tick() {
+                result = Err(e);
+            }
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        let mut timeout = match self.options.interval {
+            Some(interval) => interval
+                .checked_sub(self.last_dump.elapsed())
+                .unwrap_or_default(),
+            None => self.options.poll_interval,
+        };
+        if self.options.growth_threshold.is_some() {
+            timeout = timeout.min(self.options.poll_interval);
+        }
+        timeout
+    }
+
+    fn tick(&mut self) -> io::Result<()> {
+        let interval_due = match self.options.interval {
+            Some(interval) => self.last_dump.elapsed() >= interval,
+            None => false,
+        };
+        let allocated = match self.options.growth_threshold {
+            Some(_) => self.allocated()?,
+            None => 0,
+        };
+        let growth_due = match self.options.growth_threshold {
+            Some(threshold) => allocated >= self.last_allocated.saturating_add(threshold),
+            None => false,
+        };
+        if !interval_due && !growth_due {
+            return Ok(());
+        }
+
+        self.last_dump = Instant::now();
+        self.last_allocated = allocated;
+
+        if !self.active.get()? {
+            return Ok(());
+        }
+
+        self.dump()?;
+        prune(
+            &self.directory,
+            &self.prefix,
+            self.options.max_files,
+            self.options.max_bytes,
+        )
+    }
+
+    fn allocated(&self) -> io::Result<usize> {
+        self.epoch.advance()?;
+        self.allocated.get()
+    }
+
+    fn dump(&mut self) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let path = self.directory.join(format!(
+            "{}.{}.{}.heap",
+            self.prefix,
+            timestamp.as_millis(),
+            self.sequence,
+        ));
+        self.sequence += 1;
+
+        // jemalloc writes the profile in place, so dump to a temporary file to avoid exposing
+        // partial profiles
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let filename = tmp.to_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "non-UTF8 profile directory")
+        })?;
+        let filename =
+            CString::new(filename).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        if let Err(e) = self.dump.dump(&filename) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        fs::rename(&tmp, &path)
+    }
+}
+
+// deletes the oldest profiles in the directory until it is within the limits
+fn prune(
+    directory: &Path,
+    prefix: &str,
+    max_files: Option<usize>,
+    max_bytes: Option<u64>,
+) -> io::Result<()> {
+    let mut profiles = vec![];
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let key = match entry.file_name().to_str() {
+            Some(name) => profile_key(name, prefix),
+            None => None,
+        };
+        let key = match key {
+            Some(key) => key,
+            None => continue,
+        };
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        profiles.push((key, entry.path(), metadata.len()));
+    }
+
+    // newest first
+    profiles.sort_by(|a, b| b.cmp(a));
+
+    let mut bytes = 0;
+    for (i, (_, path, len)) in profiles.into_iter().enumerate() {
+        bytes += len;
+        if i == 0 {
+            continue;
+        }
+
+        let over_files = match max_files {
+            Some(max_files) => i >= max_files,
+            None => false,
+        };
+        let over_bytes = match max_bytes {
+            Some(max_bytes) => bytes > max_bytes,
+            None => false,
+        };
+        if over_files || over_bytes {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+// parses the timestamp and sequence number out of a profile's filename, which orders profiles by
+// age independently of filesystem timestamps
+fn profile_key(name: &str, prefix: &str) -> Option<(u64, u64)> {
+    let name = name.strip_prefix(prefix)?.strip_prefix('.')?;
+    let mut parts = name.strip_suffix(".heap")?.split('.');
+    let timestamp = parts.next()?.parse().ok()?;
+    let sequence = parts.next()?.parse().ok()?;
+    match parts.next() {
+        Some(_) => None,
+        None => Some((timestamp, sequence)),
+    }
+}
+
+fn validate(options: &Options) -> io::Result<()> {
+    let msg = if options.interval.is_none() && options.growth_threshold.is_none() {
+        "neither an interval nor a growth threshold is set"
+    } else if options.interval == Some(Duration::from_secs(0)) {
+        "the dump interval is zero"
+    } else if options.growth_threshold.is_some() && options.poll_interval == Duration::from_secs(0)
+    {
+        "the poll interval is zero"
+    } else {
+        return Ok(());
+    };
+
+    Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+
+    #[test]
+    fn invalid_options() {
+        let options = Options {
+            interval: None,
+            ..Options::default()
+        };
+        let err = Profiler::start(env::temp_dir(), options).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let options = Options {
+            interval: Some(Duration::from_secs(0)),
+            ..Options::default()
+        };
+        let err = Profiler::start(env::temp_dir(), options).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let options = Options {
+            interval: None,
+            growth_threshold: Some(1024),
+            poll_interval: Duration::from_secs(0),
+            ..Options::default()
+        };
+        let err = Profiler::start(env::temp_dir(), options).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let options = Options {
+            poll_interval: Duration::from_secs(0),
+            ..Options::default()
+        };
+        assert!(validate(&options).is_ok());
+    }
+
+    #[test]
+    fn retention() {
+        let directory = env::temp_dir().join(format!("jemalloc-ctl-prune-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        // oldest first; a lexicographic ordering would get these wrong
+        for &(timestamp, sequence) in &[(999, 0), (1000, 2), (1000, 10), (1001, 0), (1002, 0)] {
+            let name = format!("jeprof.42.{}.{}.heap", timestamp, sequence);
+            File::create(directory.join(name))
+                .unwrap()
+                .set_len(100)
+                .unwrap();
+        }
+        File::create(directory.join("other.42.0.0.heap")).unwrap();
+        File::create(directory.join("jeprof.43.0.0.heap")).unwrap();
+        File::create(directory.join("jeprof.42.1003.0.heap.tmp")).unwrap();
+        File::create(directory.join("jeprof.42.x.0.heap")).unwrap();
+
+        let names = || {
+            let mut names = fs::read_dir(&directory)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        prune(&directory, "jeprof.42", Some(4), None).unwrap();
+        assert_eq!(
+            names(),
+            [
+                "jeprof.42.1000.10.heap",
+                "jeprof.42.1000.2.heap",
+                "jeprof.42.1001.0.heap",
+                "jeprof.42.1002.0.heap",
+                "jeprof.42.1003.0.heap.tmp",
+                "jeprof.42.x.0.heap",
+                "jeprof.43.0.0.heap",
+                "other.42.0.0.heap",
+            ]
+        );
+
+        prune(&directory, "jeprof.42", None, Some(250)).unwrap();
+        assert_eq!(
+            names(),
+            [
+                "jeprof.42.1001.0.heap",
+                "jeprof.42.1002.0.heap",
+                "jeprof.42.1003.0.heap.tmp",
+                "jeprof.42.x.0.heap",
+                "jeprof.43.0.0.heap",
+                "other.42.0.0.heap",
+            ]
+        );
+
+        prune(&directory, "jeprof.42", Some(0), Some(0)).unwrap();
+        assert_eq!(
+            names(),
+            [
+                "jeprof.42.1002.0.heap",
+                "jeprof.42.1003.0.heap.tmp",
+                "jeprof.42.x.0.heap",
+                "jeprof.43.0.0.heap",
+                "other.42.0.0.heap",
+            ]
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+//! Comparison of heap profiles taken at different times.
+//!
+//! Stacks are matched by their addresses, so both profiles must have been taken by the same
+//! process. Counts are compared as [`Estimate`]s, so the profiles do not need to share a sample
+//! period.
+//!
+//! [`Estimate`]: ../struct.Estimate.html
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use profile::{call_addresses, Estimate, HeapProfile, Symbols};
+
+/// The change in the allocations of a single backtrace between two profiles.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackDiff {
+    /// The return addresses of the backtrace, innermost frame first.
+    pub addresses: Vec<u64>,
+
+    /// The estimated counts in the earlier profile.
+    ///
+    /// This is zero if the backtrace does not appear in that profile.
+    pub before: Estimate,
+
+    /// The estimated counts in the later profile.
+    ///
+    /// This is zero if the backtrace does not appear in that profile.
+    pub after: Estimate,
+}
+
+impl StackDiff {
+    /// Returns the estimated growth in the number of bytes currently allocated.
+    pub fn bytes(&self) -> f64 {
+        self.after.bytes - self.before.bytes
+    }
+
+    /// Returns the estimated growth in the number of objects currently allocated.
+    pub fn objects(&self) -> f64 {
+        self.after.objects - self.before.objects
+    }
+
+    /// Determines if any frame of the backtrace is in a function whose name contains `name`.
+    pub fn has_function(&self, symbols: &Symbols, name: &str) -> bool {
+        self.functions(symbols).any(|f| f.contains(name))
+    }
+
+    /// Determines if any frame of the backtrace is in a function defined in the crate `name`.
+    ///
+    /// Functions are attributed to crates by the leading component of their path, so trait
+    /// methods count towards the crate of the type they are implemented for. `-` and `_` are
+    /// equivalent in crate names.
+    pub fn has_crate(&self, symbols: &Symbols, name: &str) -> bool {
+        let name = name.replace('-', "_");
+        self.functions(symbols).any(|f| {
+            match f.trim_start_matches('<').strip_prefix(&*name) {
+                // v0 mangling includes a disambiguator in brackets, e.g. `std[e28293b1aa0f68bd]`
+                Some(rest) => rest.starts_with("::") || rest.starts_with('['),
+                None => false,
+            }
+        })
+    }
+
+    fn functions<'a>(&'a self, symbols: &'a Symbols) -> impl Iterator<Item = &'a str> + 'a {
+        call_addresses(&self.addresses)
+            .flat_map(move |address| symbols.get(address))
+            .filter_map(|frame| frame.function.as_deref())
+    }
+}
+
+/// The per-backtrace changes between two heap profiles.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::profile::diff::Diff;
+/// use jemalloc_ctl::profile::HeapProfile;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let before = HeapProfile::parse(&jemalloc_ctl::prof::dump_to_vec().unwrap()).unwrap();
+///     // ...
+///     let after = HeapProfile::parse(&jemalloc_ctl::prof::dump_to_vec().unwrap()).unwrap();
+///
+///     let diff = Diff::new(&before, &after);
+///     for stack in diff.stacks.iter().take(10) {
+///         println!("{:+.0} bytes {:+.0} objects {:x?}", stack.bytes(), stack.objects(), stack.addresses);
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diff {
+    /// The backtraces which appear in either profile, in descending order of byte growth.
+    pub stacks: Vec<StackDiff>,
+}
+
+impl Diff {
+    /// Compares two heap profiles.
+    ///
+    /// Backtraces whose estimated counts are unchanged are omitted.
+    pub fn new(before: &HeapProfile, after: &HeapProfile) -> Diff {
+        let mut stacks = HashMap::new();
+        for stack in &before.stacks {
+            let diff = stacks
+                .entry(&stack.addresses)
+                .or_insert_with(|| empty(&stack.addresses));
+            add(&mut diff.before, &before.estimate(&stack.total));
+        }
+        for stack in &after.stacks {
+            let diff = stacks
+                .entry(&stack.addresses)
+                .or_insert_with(|| empty(&stack.addresses));
+            add(&mut diff.after, &after.estimate(&stack.total));
+        }
+
+        let mut diff = Diff {
+            stacks: stacks
+                .into_values()
+                .filter(|diff| diff.before != diff.after)
+                .collect(),
+        };
+        diff.sort_by_bytes();
+        diff
+    }
+
+    /// Sorts the backtraces in descending order of byte growth.
+    pub fn sort_by_bytes(&mut self) {
+        self.stacks.sort_by(|a, b| descending(a.bytes(), b.bytes()));
+    }
+
+    /// Sorts the backtraces in descending order of object growth.
+    pub fn sort_by_objects(&mut self) {
+        self.stacks
+            .sort_by(|a, b| descending(a.objects(), b.objects()));
+    }
+
+    /// Retains only the backtraces which grew in bytes.
+    pub fn retain_growth(&mut self) {
+        self.stacks.retain(|s| s.bytes() > 0.);
+    }
+
+    /// Retains only the backtraces passing through a function whose name contains `name`.
+    ///
+    /// See [`StackDiff::has_function`].
+    ///
+    /// [`StackDiff::has_function`]: struct.StackDiff.html#method.has_function
+    pub fn retain_function(&mut self, symbols: &Symbols, name: &str) {
+        self.stacks.retain(|s| s.has_function(symbols, name));
+    }
+
+    /// Retains only the backtraces passing through a function defined in the crate `name`.
+    ///
+    /// See [`StackDiff::has_crate`].
+    ///
+    /// [`StackDiff::has_crate`]: struct.StackDiff.html#method.has_crate
+    pub fn retain_crate(&mut self, symbols: &Symbols, name: &str) {
+        self.stacks.retain(|s| s.has_crate(symbols, name));
+    }
+
+    /// Returns the total estimated growth in bytes across the retained backtraces.
+    pub fn bytes(&self) -> f64 {
+        self.stacks.iter().map(StackDiff::bytes).sum()
+    }
+
+    /// Returns the total estimated growth in objects across the retained backtraces.
+    pub fn objects(&self) -> f64 {
+        self.stacks.iter().map(StackDiff::objects).sum()
+    }
+}
+
+fn empty(addresses: &[u64]) -> StackDiff {
+    StackDiff {
+        addresses: addresses.to_vec(),
+        before: Estimate::default(),
+        after: Estimate::default(),
+    }
+}
+
+fn add(a: &mut Estimate, b: &Estimate) {
+    a.objects += b.objects;
+    a.bytes += b.bytes;
+    a.cumulative_objects += b.cumulative_objects;
+    a.cumulative_bytes += b.cumulative_bytes;
+}
+
+fn descending(a: f64, b: f64) -> Ordering {
+    b.partial_cmp(&a).unwrap_or(Ordering::Equal)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use profile::Frame;
+
+    fn profile(stacks: &str) -> HeapProfile {
+        // a sample period of 0 disables estimation
+        HeapProfile::parse(format!("heap_v2/0\n  t*: 0: 0 [0: 0]\n{}", stacks).as_bytes()).unwrap()
+    }
+
+    fn sample_diff() -> Diff {
+        let before = profile(
+            "@ 0x10 0x21\n  t*: 1: 1024 [0: 0]\n\
+             @ 0x10 0x31\n  t*: 2: 2048 [0: 0]\n\
+             @ 0x10 0x41\n  t*: 1: 64 [0: 0]\n",
+        );
+        let after = profile(
+            "@ 0x10 0x21\n  t*: 3: 3072 [0: 0]\n\
+             @ 0x10 0x31\n  t*: 1: 1024 [0: 0]\n\
+             @ 0x10 0x41\n  t*: 1: 64 [0: 0]\n\
+             @ 0x10 0x51\n  t*: 8: 256 [0: 0]\n",
+        );
+        Diff::new(&before, &after)
+    }
+
+    fn symbols() -> Symbols {
+        let frame = |function: &str| Frame {
+            function: Some(function.to_string()),
+            file: None,
+            line: None,
+        };
+        let mut symbols = Symbols::new();
+        symbols.insert(0x10, vec![frame("alloc::alloc::alloc")]);
+        symbols.insert(0x20, vec![frame("my_app::cache::Cache::insert")]);
+        symbols.insert(
+            0x30,
+            vec![frame("std[e28293b1aa0f68bd]::io::stdio::stdout")],
+        );
+        symbols.insert(
+            0x50,
+            vec![frame("<my_app::Request as core::clone::Clone>::clone")],
+        );
+        symbols
+    }
+
+    #[test]
+    fn rank() {
+        let mut diff = sample_diff();
+        let addresses = |diff: &Diff| {
+            diff.stacks
+                .iter()
+                .map(|s| s.addresses[1])
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(addresses(&diff), [0x21, 0x51, 0x31]);
+        assert_eq!(diff.stacks[0].bytes(), 2048.);
+        assert_eq!(diff.stacks[0].objects(), 2.);
+        assert_eq!(diff.stacks[2].bytes(), -1024.);
+        assert_eq!(diff.bytes(), 2048. + 256. - 1024.);
+
+        diff.sort_by_objects();
+        assert_eq!(addresses(&diff), [0x51, 0x21, 0x31]);
+
+        diff.retain_growth();
+        assert_eq!(addresses(&diff), [0x51, 0x21]);
+    }
+
+    #[test]
+    fn filter() {
+        let symbols = symbols();
+
+        let mut diff = sample_diff();
+        diff.retain_function(&symbols, "Cache::insert");
+        assert_eq!(diff.stacks.len(), 1);
+        assert_eq!(diff.stacks[0].addresses[1], 0x21);
+
+        let mut diff = sample_diff();
+        diff.retain_crate(&symbols, "my-app");
+        assert_eq!(diff.stacks.len(), 2);
+
+        let mut diff = sample_diff();
+        diff.retain_crate(&symbols, "std");
+        assert_eq!(diff.stacks.len(), 1);
+        assert_eq!(diff.stacks[0].addresses[1], 0x31);
+
+        let mut diff = sample_diff();
+        diff.retain_crate(&symbols, "my");
+        assert!(diff.stacks.is_empty());
+    }
+}
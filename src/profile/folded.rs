@@ -0,0 +1,325 @@
+//! Folded stack output for heap profiles.
+//!
+//! The folded format has one line per unique stack, consisting of the stack's frames from the
+//! outermost inward separated by `;`, followed by a space and the stack's weight. It is the input
+//! format of flamegraph tools such as `inferno-flamegraph` and `flamegraph.pl`.
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use prof;
+use profile::{HeapProfile, Symbols};
+
+/// The quantity stacks are weighted by.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Weight {
+    /// The number of bytes currently allocated.
+    Bytes,
+    /// The number of objects currently allocated.
+    Objects,
+}
+
+/// Options controlling folded stack output.
+///
+/// Options are constructed with `Options::default()`, and the fields then set individually.
+#[derive(Copy, Clone, Debug)]
+#[non_exhaustive]
+pub struct Options<'a> {
+    /// The quantity stacks are weighted by.
+    ///
+    /// Defaults to `Weight::Bytes`.
+    pub weight: Weight,
+
+    /// If set, sampled counts are scaled up to estimates of the true counts as described in
+    /// [`HeapProfile::estimate`]. Otherwise, the raw sampled counts are used.
+    ///
+    /// Defaults to `true`.
+    ///
+    /// [`HeapProfile::estimate`]: ../struct.HeapProfile.html#method.estimate
+    pub estimate: bool,
+
+    /// A symbol table used to name frames.
+    ///
+    /// Frames which are not symbolized are named by their address. Defaults to `None`.
+    pub symbols: Option<&'a Symbols>,
+
+    /// A profile whose weights are subtracted from those of the profile being written.
+    ///
+    /// Stacks are matched by their folded representation. Only stacks which grew relative to the
+    /// baseline are written. Defaults to `None`.
+    pub baseline: Option<&'a HeapProfile>,
+}
+
+impl<'a> Default for Options<'a> {
+    fn default() -> Options<'a> {
+        Options {
+            weight: Weight::Bytes,
+            estimate: true,
+            symbols: None,
+            baseline: None,
+        }
+    }
+}
+
+/// Writes a heap profile as folded stacks.
+///
+/// Stacks with a weight of zero are skipped. If a stack is symbolized, frames inside jemalloc
+/// itself are removed, as `jeprof` does.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::profile::folded::{self, Options, Weight};
+/// use jemalloc_ctl::profile::HeapProfile;
+/// use std::fs::File;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let baseline = HeapProfile::parse(&jemalloc_ctl::prof::dump_to_vec().unwrap()).unwrap();
+///     // ...
+///     let profile = HeapProfile::parse(&jemalloc_ctl::prof::dump_to_vec().unwrap()).unwrap();
+///
+///     let mut options = Options::default();
+///     options.weight = Weight::Objects;
+///     options.baseline = Some(&baseline);
+///     let file = File::create("heap.folded").unwrap();
+///     folded::write(file, &profile, &options).unwrap();
+/// }
+/// ```
+pub fn write<W>(mut writer: W, profile: &HeapProfile, options: &Options) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut stacks = fold(profile, options);
+    if let Some(baseline) = options.baseline {
+        for (stack, weight) in fold(baseline, options) {
+            *stacks.entry(stack).or_insert(0) -= weight;
+        }
+    }
+
+    for (stack, weight) in stacks {
+        if weight > 0 {
+            writeln!(writer, "{} {}", stack, weight)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Dumps a heap profile of the current process and writes it as folded stacks.
+///
+/// When the `symbolize` Cargo feature is enabled, frames are symbolized in-process. Otherwise,
+/// they are named by their address.
+///
+/// See [`prof::dump_to_writer`] for the requirements on jemalloc's configuration.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::profile::folded::{self, Weight};
+/// use std::io;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let stdout = io::stdout();
+///     folded::dump(stdout.lock(), Weight::Bytes).unwrap();
+/// }
+/// ```
+///
+/// [`prof::dump_to_writer`]: ../../prof/fn.dump_to_writer.html
+pub fn dump<W>(writer: W, weight: Weight) -> io::Result<()>
+where
+    W: Write,
+{
+    let profile = HeapProfile::parse(&prof::dump_to_vec()?)?;
+    #[cfg(feature = "symbolize")]
+    let symbols = Some(::profile::symbolize::symbolize(&profile)?);
+    #[cfg(not(feature = "symbolize"))]
+    let symbols = None;
+
+    let options = Options {
+        weight,
+        symbols: symbols.as_ref(),
+        ..Options::default()
+    };
+    write(writer, &profile, &options)
+}
+
+// folds the stacks of a profile, merging those which fold to the same frames
+fn fold(profile: &HeapProfile, options: &Options) -> BTreeMap<String, i64> {
+    let mut stacks = BTreeMap::new();
+
+    for stack in &profile.stacks {
+        let weight = if options.estimate {
+            let estimate = profile.estimate(&stack.total);
+            match options.weight {
+                Weight::Bytes => estimate.bytes.round() as i64,
+                Weight::Objects => estimate.objects.round() as i64,
+            }
+        } else {
+            match options.weight {
+                Weight::Bytes => stack.total.bytes as i64,
+                Weight::Objects => stack.total.objects as i64,
+            }
+        };
+
+        let mut frames = vec![];
+        for address in stack.call_addresses() {
+            let symbolized = options.symbols.map_or(&[][..], |s| s.get(address));
+            let mut named = false;
+            for frame in symbolized {
+                if let Some(ref function) = frame.function {
+                    // `;` separates frames, but can appear in names like `[u8; 4]`
+                    frames.push(function.replace(';', ":"));
+                    named = true;
+                }
+            }
+            if !named {
+                frames.push(format!("{:#x}", address));
+            }
+        }
+        if let Some(i) = frames.iter().position(|f| is_entry_point(f)) {
+            frames.drain(..=i);
+        }
+        frames.reverse();
+
+        *stacks.entry(frames.join(";")).or_insert(0) += weight;
+    }
+
+    stacks
+}
+
+// jemalloc's own frames are at the top of every stack, down to the function that was called into
+fn is_entry_point(function: &str) -> bool {
+    let function = function.strip_prefix("_rjem_").unwrap_or(function);
+    matches!(
+        function,
+        "malloc"
+            | "calloc"
+            | "realloc"
+            | "posix_memalign"
+            | "aligned_alloc"
+            | "memalign"
+            | "valloc"
+            | "mallocx"
+            | "rallocx"
+            | "xallocx"
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use profile::Frame;
+
+    fn profile(stacks: &str) -> HeapProfile {
+        HeapProfile::parse(format!("heap_v2/524288\n  t*: 0: 0 [0: 0]\n{}", stacks).as_bytes())
+            .unwrap()
+    }
+
+    fn folded(profile: &HeapProfile, options: &Options) -> String {
+        let mut buf = vec![];
+        write(&mut buf, profile, options).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn addresses() {
+        let profile = profile(
+            "@ 0x10 0x21 0x31\n  t*: 2: 2048 [0: 0]\n\
+             @ 0x11 0x41\n  t*: 1: 0 [0: 0]\n",
+        );
+
+        let mut options = Options {
+            estimate: false,
+            ..Options::default()
+        };
+        assert_eq!(folded(&profile, &options), "0x30;0x20;0x10 2048\n");
+
+        options.weight = Weight::Objects;
+        assert_eq!(
+            folded(&profile, &options),
+            "0x30;0x20;0x10 2\n0x40;0x11 1\n"
+        );
+    }
+
+    #[test]
+    fn symbolized() {
+        let profile = profile(
+            "@ 0x10 0x21 0x31\n  t*: 1: 1024 [0: 0]\n\
+             @ 0x10 0x23 0x31\n  t*: 1: 1024 [0: 0]\n",
+        );
+
+        let frame = |function: &str| Frame {
+            function: Some(function.to_string()),
+            file: None,
+            line: None,
+        };
+        let mut symbols = Symbols::new();
+        symbols.insert(0x10, vec![frame("imalloc"), frame("_rjem_mallocx")]);
+        // two call sites in the same function
+        symbols.insert(0x20, vec![frame("alloc")]);
+        symbols.insert(0x22, vec![frame("alloc")]);
+        symbols.insert(0x30, vec![frame("inner::<[u8; 4]>"), frame("outer")]);
+
+        let options = Options {
+            estimate: false,
+            symbols: Some(&symbols),
+            ..Options::default()
+        };
+        assert_eq!(
+            folded(&profile, &options),
+            "outer;inner::<[u8: 4]>;alloc 2048\n"
+        );
+    }
+
+    #[test]
+    fn baseline() {
+        let baseline = profile(
+            "@ 0x10 0x21\n  t*: 1: 1024 [0: 0]\n\
+             @ 0x10 0x31\n  t*: 2: 2048 [0: 0]\n",
+        );
+        let profile = profile(
+            "@ 0x10 0x21\n  t*: 3: 3072 [0: 0]\n\
+             @ 0x10 0x31\n  t*: 1: 1024 [0: 0]\n\
+             @ 0x10 0x41\n  t*: 1: 1024 [0: 0]\n",
+        );
+
+        let options = Options {
+            estimate: false,
+            baseline: Some(&baseline),
+            ..Options::default()
+        };
+        assert_eq!(
+            folded(&profile, &options),
+            "0x20;0x10 2048\n0x40;0x10 1024\n"
+        );
+    }
+
+    #[test]
+    fn estimate() {
+        let profile = profile("@ 0x10\n  t*: 1: 1024 [0: 0]\n");
+
+        let weight = folded(&profile, &Options::default())
+            .trim()
+            .rsplit(' ')
+            .next()
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        assert_eq!(
+            weight,
+            profile.estimate(&profile.stacks[0].total).bytes.round() as u64
+        );
+        assert!(weight > 1024);
+    }
+}
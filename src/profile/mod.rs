@@ -0,0 +1,527 @@
+//! Heap profile parsing.
+//!
+//! jemalloc writes heap profiles (see [`prof::dump`]) in a text format originally designed for
+//! `jeprof`. This module parses that format into a [`HeapProfile`].
+//!
+//! Profiles are built from samples rather than every allocation, so the raw counts underestimate
+//! the true values. [`HeapProfile::estimate`] corrects for that using the profile's sample period.
+//!
+//! [`prof::dump`]: ../prof/fn.dump.html
+//! [`HeapProfile`]: struct.HeapProfile.html
+//! [`HeapProfile::estimate`]: struct.HeapProfile.html#method.estimate
+//!
+//! Profiles only record raw addresses. The [`symbolize`] module (which requires the `symbolize`
+//! Cargo feature) resolves them to [`Symbols`] using the running process's debug information.
+//!
+//! [`symbolize`]: symbolize/index.html
+//! [`Symbols`]: struct.Symbols.html
+//!
+//! Parsed profiles can be converted to pprof's format with the [`pprof`] module, and to folded
+//! stacks for flamegraph tools with the [`folded`] module. The [`diff`] module compares profiles
+//! taken at different times.
+//!
+//! [`pprof`]: pprof/index.html
+//! [`folded`]: folded/index.html
+//! [`diff`]: diff/index.html
+use std::collections::HashMap;
+use std::io;
+
+pub mod diff;
+pub mod folded;
+pub mod pprof;
+#[cfg(feature = "symbolize")]
+pub mod symbolize;
+
+/// Object and byte counts for some subset of a heap profile's samples.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+    /// The number of sampled objects currently allocated.
+    pub objects: u64,
+
+    /// The number of sampled bytes currently allocated.
+    pub bytes: u64,
+
+    /// The number of sampled objects allocated since profiling started.
+    ///
+    /// This is only tracked if the `prof_accum` option is enabled.
+    pub cumulative_objects: u64,
+
+    /// The number of sampled bytes allocated since profiling started.
+    ///
+    /// This is only tracked if the `prof_accum` option is enabled.
+    pub cumulative_bytes: u64,
+}
+
+/// An estimate of the true object and byte counts corresponding to a set of sampled [`Counts`].
+///
+/// [`Counts`]: struct.Counts.html
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Estimate {
+    /// The estimated number of objects currently allocated.
+    pub objects: f64,
+
+    /// The estimated number of bytes currently allocated.
+    pub bytes: f64,
+
+    /// The estimated number of objects allocated since profiling started.
+    pub cumulative_objects: f64,
+
+    /// The estimated number of bytes allocated since profiling started.
+    pub cumulative_bytes: f64,
+}
+
+/// Sampled counts attributed to a single thread.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadCounts {
+    /// The jemalloc-assigned ID of the thread.
+    pub thread: u64,
+
+    /// The thread's profiling name, if it has one.
+    pub name: Option<String>,
+
+    /// The thread's counts.
+    pub counts: Counts,
+}
+
+/// The samples allocated from a single backtrace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stack {
+    /// The return addresses of the backtrace, innermost frame first.
+    pub addresses: Vec<u64>,
+
+    /// The counts for this backtrace across all threads.
+    pub total: Counts,
+
+    /// The counts for this backtrace broken down by thread.
+    pub threads: Vec<ThreadCounts>,
+}
+
+/// A memory mapping of the profiled process at the time the profile was taken.
+///
+/// These correspond to the lines of `/proc/self/maps`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MappedLibrary {
+    /// The address of the start of the mapping.
+    pub start: u64,
+
+    /// The address of the end of the mapping (exclusive).
+    pub end: u64,
+
+    /// The permissions of the mapping, e.g. `r-xp`.
+    pub permissions: String,
+
+    /// The offset of the mapping into the mapped file.
+    pub offset: u64,
+
+    /// The path of the mapped file, or a pseudo-path like `[heap]`.
+    ///
+    /// This is empty for anonymous mappings.
+    pub path: String,
+}
+
+impl Stack {
+    /// Returns the addresses of the backtrace adjusted to point into call instructions.
+    ///
+    /// All addresses other than the innermost are return addresses, which point to the
+    /// instruction after the call. They are adjusted to point into the call itself so that they
+    /// symbolize to the correct line, as `jeprof` does.
+    pub fn call_addresses<'a>(&'a self) -> impl Iterator<Item = u64> + 'a {
+        call_addresses(&self.addresses)
+    }
+}
+
+impl MappedLibrary {
+    /// Determines if an address lies within this mapping.
+    pub fn contains(&self, address: u64) -> bool {
+        self.start <= address && address < self.end
+    }
+
+    /// Determines if this mapping is executable.
+    pub fn is_executable(&self) -> bool {
+        self.permissions.as_bytes().get(2) == Some(&b'x')
+    }
+}
+
+/// A source-level stack frame.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Frame {
+    /// The demangled name of the function.
+    pub function: Option<String>,
+
+    /// The path of the source file.
+    pub file: Option<String>,
+
+    /// The line number in the source file.
+    pub line: Option<u32>,
+}
+
+/// A table mapping addresses to source-level frames.
+///
+/// Addresses are the [`call_addresses`] of a profile's stacks. A table can be built in-process
+/// with the [`symbolize`] module, or populated from some other source.
+///
+/// [`call_addresses`]: struct.Stack.html#method.call_addresses
+/// [`symbolize`]: symbolize/index.html
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    frames: HashMap<u64, Vec<Frame>>,
+}
+
+impl Symbols {
+    /// Returns a new, empty `Symbols`.
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// Records the frames corresponding to an address.
+    ///
+    /// If functions were inlined at the address, there are multiple frames, innermost first.
+    pub fn insert(&mut self, address: u64, frames: Vec<Frame>) {
+        self.frames.insert(address, frames);
+    }
+
+    /// Returns the frames corresponding to an address, innermost first.
+    ///
+    /// An empty slice is returned if the address was not symbolized.
+    pub fn get(&self, address: u64) -> &[Frame] {
+        self.frames.get(&address).map_or(&[], |f| &f[..])
+    }
+}
+
+/// A parsed jemalloc heap profile.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::profile::HeapProfile;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let dump = jemalloc_ctl::prof::dump_to_vec().unwrap();
+///     let profile = HeapProfile::parse(&dump).unwrap();
+///
+///     let estimate = profile.estimate(&profile.total);
+///     println!("~{} bytes allocated in {} stacks", estimate.bytes, profile.stacks.len());
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeapProfile {
+    /// The average number of bytes allocated between samples.
+    ///
+    /// This is `2 ^ lg_prof_sample`.
+    pub sample_period: u64,
+
+    /// The counts of all samples.
+    pub total: Counts,
+
+    /// The counts of all samples broken down by thread.
+    pub threads: Vec<ThreadCounts>,
+
+    /// The samples broken down by backtrace.
+    pub stacks: Vec<Stack>,
+
+    /// The memory mappings of the process.
+    pub mapped_libraries: Vec<MappedLibrary>,
+}
+
+impl HeapProfile {
+    /// Parses a heap profile.
+    ///
+    /// Only the `heap_v2` format written by jemalloc 4 and newer is supported.
+    pub fn parse(buf: &[u8]) -> io::Result<HeapProfile> {
+        let s = String::from_utf8_lossy(buf);
+        let mut lines = s.lines().enumerate().map(|(i, line)| (i + 1, line));
+
+        let (n, header) = lines.next().ok_or_else(|| error(1, "empty profile"))?;
+        let sample_period = match header.trim_end().strip_prefix("heap_v2/") {
+            Some(period) => period
+                .parse()
+                .map_err(|_| error(n, "invalid sample period"))?,
+            None => return Err(error(n, "expected `heap_v2/<period>` header")),
+        };
+
+        let mut profile = HeapProfile {
+            sample_period,
+            total: Counts::default(),
+            threads: vec![],
+            stacks: vec![],
+            mapped_libraries: vec![],
+        };
+        let mut seen_total = false;
+
+        for (n, line) in &mut lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            if line == "MAPPED_LIBRARIES:" {
+                break;
+            }
+
+            if let Some(addresses) = line.strip_prefix('@') {
+                let addresses = addresses
+                    .split_whitespace()
+                    .map(|a| parse_hex(a).ok_or_else(|| error(n, "invalid address")))
+                    .collect::<io::Result<Vec<_>>>()?;
+                profile.stacks.push(Stack {
+                    addresses,
+                    total: Counts::default(),
+                    threads: vec![],
+                });
+                seen_total = false;
+                continue;
+            }
+
+            let (thread, counts) =
+                parse_counts_line(line).ok_or_else(|| error(n, "invalid line"))?;
+            let (total, threads) = match profile.stacks.last_mut() {
+                Some(stack) => (&mut stack.total, &mut stack.threads),
+                None => (&mut profile.total, &mut profile.threads),
+            };
+            match thread {
+                None if !seen_total => {
+                    *total = counts;
+                    seen_total = true;
+                }
+                None => return Err(error(n, "duplicate `t*` line")),
+                Some(_) if !seen_total => return Err(error(n, "expected `t*` line")),
+                Some((thread, name)) => threads.push(ThreadCounts {
+                    thread,
+                    name,
+                    counts,
+                }),
+            }
+        }
+
+        for (n, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let library = parse_mapping(line).ok_or_else(|| error(n, "invalid mapping"))?;
+            profile.mapped_libraries.push(library);
+        }
+
+        Ok(profile)
+    }
+
+    /// Returns an estimate of the true counts corresponding to a set of sampled counts.
+    ///
+    /// Larger allocations are more likely to be sampled than small ones, so each count is scaled
+    /// based on the average sampled allocation size. This is the same adjustment `jeprof` makes.
+    pub fn estimate(&self, counts: &Counts) -> Estimate {
+        let (objects, bytes) = unsample(counts.objects, counts.bytes, self.sample_period);
+        let (cumulative_objects, cumulative_bytes) = unsample(
+            counts.cumulative_objects,
+            counts.cumulative_bytes,
+            self.sample_period,
+        );
+        Estimate {
+            objects,
+            bytes,
+            cumulative_objects,
+            cumulative_bytes,
+        }
+    }
+
+    /// Returns the mapping containing an address, if there is one.
+    pub fn library(&self, address: u64) -> Option<&MappedLibrary> {
+        self.mapped_libraries.iter().find(|l| l.contains(address))
+    }
+}
+
+fn unsample(objects: u64, bytes: u64, sample_period: u64) -> (f64, f64) {
+    if objects == 0 || sample_period == 0 {
+        return (objects as f64, bytes as f64);
+    }
+
+    let ratio = bytes as f64 / objects as f64 / sample_period as f64;
+    let scale = 1. / (1. - (-ratio).exp());
+    (objects as f64 * scale, bytes as f64 * scale)
+}
+
+// adjusts the return addresses of a backtrace, innermost frame first, to point into their call
+// instructions
+fn call_addresses<'a>(addresses: &'a [u64]) -> impl Iterator<Item = u64> + 'a {
+    addresses.iter().enumerate().map(|(i, &address)| {
+        if i == 0 {
+            address
+        } else {
+            address.saturating_sub(1)
+        }
+    })
+}
+
+fn error(line: usize, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, msg),
+    )
+}
+
+// splits off the first whitespace-delimited field
+fn next_field(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start();
+    if s.is_empty() {
+        return None;
+    }
+    match s.find(char::is_whitespace) {
+        Some(i) => Some((&s[..i], &s[i..])),
+        None => Some((s, "")),
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(s, 16).ok()
+}
+
+// parses `  t<id>: <objs>: <bytes> [<objs>: <bytes>][ <name>]`, where the ID is `None` for `t*`
+#[allow(clippy::type_complexity)]
+fn parse_counts_line(line: &str) -> Option<(Option<(u64, Option<String>)>, Counts)> {
+    let (id, rest) = next_field(line)?;
+    let id = id.strip_prefix('t')?.strip_suffix(':')?;
+    let (objects, rest) = next_field(rest)?;
+    let (bytes, rest) = next_field(rest)?;
+    let (cumulative_objects, rest) = next_field(rest)?;
+    let (cumulative_bytes, rest) = next_field(rest)?;
+
+    let counts = Counts {
+        objects: objects.strip_suffix(':')?.parse().ok()?,
+        bytes: bytes.parse().ok()?,
+        cumulative_objects: cumulative_objects
+            .strip_prefix('[')?
+            .strip_suffix(':')?
+            .parse()
+            .ok()?,
+        cumulative_bytes: cumulative_bytes.strip_suffix(']')?.parse().ok()?,
+    };
+
+    let thread = if id == "*" {
+        None
+    } else {
+        let name = rest.trim();
+        let name = if name.is_empty() {
+            None
+        } else {
+            Some(name.to_string())
+        };
+        Some((id.parse().ok()?, name))
+    };
+
+    Some((thread, counts))
+}
+
+// parses a line of `/proc/self/maps`
+fn parse_mapping(line: &str) -> Option<MappedLibrary> {
+    let (range, rest) = next_field(line)?;
+    let (permissions, rest) = next_field(rest)?;
+    let (offset, rest) = next_field(rest)?;
+    let (_dev, rest) = next_field(rest)?;
+    let (_inode, rest) = next_field(rest)?;
+
+    let mut range = range.splitn(2, '-');
+    Some(MappedLibrary {
+        start: parse_hex(range.next()?)?,
+        end: parse_hex(range.next()?)?,
+        permissions: permissions.to_string(),
+        offset: parse_hex(offset)?,
+        path: rest.trim().to_string(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PROFILE: &str = "\
+heap_v2/524288
+  t*: 3: 2099200 [0: 0]
+  t0: 2: 2098176 [0: 0]
+  t1: 1: 1024 [0: 0] worker
+@ 0x5597a11e3e98 0x5597a10b3c52 0x7f6c269c924a
+  t*: 2: 2098176 [0: 0]
+  t0: 2: 2098176 [0: 0]
+@ 0x5597a11e3e98 0x5597a1047459
+  t*: 1: 1024 [0: 0]
+  t1: 1: 1024 [0: 0] worker
+
+MAPPED_LIBRARIES:
+5597a1008000-5597a1046000 r--p 00000000 fe:00 1082785                    /usr/bin/my app
+5597a1046000-5597a12d0000 r-xp 0003d000 fe:00 1082785                    /usr/bin/my app
+5597a12da000-5597a133a000 rw-p 00000000 00:00 0 
+7f6c269c8000-7f6c26b1e000 r-xp 00026000 fe:00 395379                     /usr/lib/libc.so.6
+";
+
+    #[test]
+    fn parse() {
+        let profile = HeapProfile::parse(PROFILE.as_bytes()).unwrap();
+
+        assert_eq!(profile.sample_period, 524288);
+        assert_eq!(
+            profile.total,
+            Counts {
+                objects: 3,
+                bytes: 2099200,
+                cumulative_objects: 0,
+                cumulative_bytes: 0,
+            }
+        );
+        assert_eq!(profile.threads.len(), 2);
+        assert_eq!(profile.threads[1].thread, 1);
+        assert_eq!(profile.threads[1].name, Some("worker".to_string()));
+        assert_eq!(profile.threads[0].name, None);
+
+        assert_eq!(profile.stacks.len(), 2);
+        assert_eq!(
+            profile.stacks[0].addresses,
+            [0x5597a11e3e98, 0x5597a10b3c52, 0x7f6c269c924a]
+        );
+        assert_eq!(profile.stacks[0].total.bytes, 2098176);
+        assert_eq!(profile.stacks[1].threads[0].counts.objects, 1);
+
+        assert_eq!(profile.mapped_libraries.len(), 4);
+        let app = &profile.mapped_libraries[1];
+        assert_eq!(app.start, 0x5597a1046000);
+        assert_eq!(app.end, 0x5597a12d0000);
+        assert_eq!(app.offset, 0x3d000);
+        assert_eq!(app.path, "/usr/bin/my app");
+        assert!(app.is_executable());
+        assert_eq!(profile.mapped_libraries[2].path, "");
+
+        assert_eq!(
+            profile.library(0x7f6c269c924a).unwrap().path,
+            "/usr/lib/libc.so.6"
+        );
+        assert!(profile.library(0x1).is_none());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(HeapProfile::parse(b"").is_err());
+        assert!(HeapProfile::parse(b"heap profile: 1: 2 [3: 4] @ heapprofile\n").is_err());
+        assert!(HeapProfile::parse(b"heap_v2/524288\n  t0: 1: 1 [0: 0]\n").is_err());
+        assert!(HeapProfile::parse(b"heap_v2/524288\n  t*: 1: 1 [0: 0]\n@ 0xzz\n").is_err());
+    }
+
+    #[test]
+    fn estimate() {
+        let profile = HeapProfile::parse(PROFILE.as_bytes()).unwrap();
+
+        // allocations larger than the sample period are scaled up only a little
+        let estimate = profile.estimate(&profile.stacks[0].total);
+        assert!(estimate.bytes > 2098176.);
+        assert!(estimate.bytes < 2098176. * 1.2);
+
+        // small allocations are scaled up by roughly the sample period over their size
+        let estimate = profile.estimate(&profile.stacks[1].total);
+        assert!((estimate.objects - 512.5).abs() < 1.);
+        assert!((estimate.bytes - 524800.).abs() < 1024.);
+
+        assert_eq!(estimate.cumulative_bytes, 0.);
+    }
+}
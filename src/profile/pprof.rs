@@ -0,0 +1,500 @@
+//! Conversion of heap profiles to the pprof format.
+//!
+//! The output is an uncompressed `profile.proto` message as consumed by `pprof` and compatible
+//! tools. Each stack produces a sample with `alloc_objects`, `alloc_space`, `inuse_objects`, and
+//! `inuse_space` values, estimated from the sampled counts as described in
+//! [`HeapProfile::estimate`]. The `alloc_*` values are only nonzero if the `prof_accum` option is
+//! enabled.
+//!
+//! Addresses are attributed to the executable mappings recorded in the profile. They can
+//! optionally be symbolized with a [`Symbols`] table.
+//!
+//! [`HeapProfile::estimate`]: ../struct.HeapProfile.html#method.estimate
+//! [`Symbols`]: ../struct.Symbols.html
+use std::collections::HashMap;
+
+use profile::{Frame, HeapProfile, Symbols};
+
+// field numbers from profile.proto
+const PROFILE_SAMPLE_TYPE: u32 = 1;
+const PROFILE_SAMPLE: u32 = 2;
+const PROFILE_MAPPING: u32 = 3;
+const PROFILE_LOCATION: u32 = 4;
+const PROFILE_FUNCTION: u32 = 5;
+const PROFILE_STRING_TABLE: u32 = 6;
+const PROFILE_PERIOD_TYPE: u32 = 11;
+const PROFILE_PERIOD: u32 = 12;
+const PROFILE_DEFAULT_SAMPLE_TYPE: u32 = 14;
+
+const VALUE_TYPE_TYPE: u32 = 1;
+const VALUE_TYPE_UNIT: u32 = 2;
+
+const SAMPLE_LOCATION_ID: u32 = 1;
+const SAMPLE_VALUE: u32 = 2;
+
+const MAPPING_ID: u32 = 1;
+const MAPPING_MEMORY_START: u32 = 2;
+const MAPPING_MEMORY_LIMIT: u32 = 3;
+const MAPPING_FILE_OFFSET: u32 = 4;
+const MAPPING_FILENAME: u32 = 5;
+const MAPPING_HAS_FUNCTIONS: u32 = 7;
+const MAPPING_HAS_FILENAMES: u32 = 8;
+const MAPPING_HAS_LINE_NUMBERS: u32 = 9;
+const MAPPING_HAS_INLINE_FRAMES: u32 = 10;
+
+const LOCATION_ID: u32 = 1;
+const LOCATION_MAPPING_ID: u32 = 2;
+const LOCATION_ADDRESS: u32 = 3;
+const LOCATION_LINE: u32 = 4;
+
+const LINE_FUNCTION_ID: u32 = 1;
+const LINE_LINE: u32 = 2;
+
+const FUNCTION_ID: u32 = 1;
+const FUNCTION_NAME: u32 = 2;
+const FUNCTION_SYSTEM_NAME: u32 = 3;
+const FUNCTION_FILENAME: u32 = 4;
+
+const SAMPLE_TYPES: [(&str, &str); 4] = [
+    ("alloc_objects", "count"),
+    ("alloc_space", "bytes"),
+    ("inuse_objects", "count"),
+    ("inuse_space", "bytes"),
+];
+
+/// Encodes a heap profile as a pprof `profile.proto` message.
+///
+/// Location addresses are the stacks' [`call_addresses`].
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::profile::{pprof, HeapProfile};
+/// use std::fs;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let dump = jemalloc_ctl::prof::dump_to_vec().unwrap();
+///     let profile = HeapProfile::parse(&dump).unwrap();
+///
+///     fs::write("heap.pb", pprof::encode(&profile)).unwrap();
+/// }
+/// ```
+///
+/// [`call_addresses`]: ../struct.Stack.html#method.call_addresses
+pub fn encode(profile: &HeapProfile) -> Vec<u8> {
+    encode_inner(profile, None)
+}
+
+/// Encodes a heap profile as a pprof `profile.proto` message, including function names, files,
+/// and line numbers from a symbol table.
+///
+/// The symbol table can be built by the `symbolize` module when the `symbolize` Cargo feature is
+/// enabled, or populated from some other source.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::profile::{pprof, Frame, HeapProfile, Symbols};
+/// use std::fs;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let dump = jemalloc_ctl::prof::dump_to_vec().unwrap();
+///     let profile = HeapProfile::parse(&dump).unwrap();
+///
+///     let mut symbols = Symbols::new();
+///     symbols.insert(
+///         0x55d0_0000_1234,
+///         vec![Frame {
+///             function: Some("my_crate::build_cache".to_string()),
+///             file: Some("src/cache.rs".to_string()),
+///             line: Some(42),
+///         }],
+///     );
+///
+///     fs::write("heap.pb", pprof::encode_symbolized(&profile, &symbols)).unwrap();
+/// }
+/// ```
+pub fn encode_symbolized(profile: &HeapProfile, symbols: &Symbols) -> Vec<u8> {
+    encode_inner(profile, Some(symbols))
+}
+
+fn encode_inner(profile: &HeapProfile, symbols: Option<&Symbols>) -> Vec<u8> {
+    let mut strings = StringTable::new();
+    let mut out = vec![];
+
+    for &(type_, unit) in &SAMPLE_TYPES {
+        let value_type = value_type(&mut strings, type_, unit);
+        message_field(&mut out, PROFILE_SAMPLE_TYPE, &value_type);
+    }
+
+    // only executable mappings with a backing file are useful for symbolization
+    let mut mapping_ids = vec![];
+    for library in &profile.mapped_libraries {
+        if !library.is_executable() || library.path.is_empty() || library.path.starts_with('[') {
+            continue;
+        }
+
+        let id = mapping_ids.len() as u64 + 1;
+        mapping_ids.push((library, id));
+
+        let mut mapping = vec![];
+        uint64_field(&mut mapping, MAPPING_ID, id);
+        uint64_field(&mut mapping, MAPPING_MEMORY_START, library.start);
+        uint64_field(&mut mapping, MAPPING_MEMORY_LIMIT, library.end);
+        uint64_field(&mut mapping, MAPPING_FILE_OFFSET, library.offset);
+        uint64_field(&mut mapping, MAPPING_FILENAME, strings.get(&library.path));
+        if symbols.is_some() {
+            uint64_field(&mut mapping, MAPPING_HAS_FUNCTIONS, 1);
+            uint64_field(&mut mapping, MAPPING_HAS_FILENAMES, 1);
+            uint64_field(&mut mapping, MAPPING_HAS_LINE_NUMBERS, 1);
+            uint64_field(&mut mapping, MAPPING_HAS_INLINE_FRAMES, 1);
+        }
+        message_field(&mut out, PROFILE_MAPPING, &mapping);
+    }
+
+    let mut location_ids = HashMap::new();
+    let mut locations = vec![];
+    for stack in &profile.stacks {
+        let ids = stack
+            .call_addresses()
+            .map(|address| {
+                let next_id = location_ids.len() as u64 + 1;
+                *location_ids.entry(address).or_insert_with(|| {
+                    let mapping_id = mapping_ids
+                        .iter()
+                        .find(|&&(library, _)| library.contains(address))
+                        .map_or(0, |&(_, id)| id);
+                    locations.push((next_id, mapping_id, address));
+                    next_id
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let estimate = profile.estimate(&stack.total);
+        let values = [
+            estimate.cumulative_objects.round() as u64,
+            estimate.cumulative_bytes.round() as u64,
+            estimate.objects.round() as u64,
+            estimate.bytes.round() as u64,
+        ];
+
+        let mut sample = vec![];
+        packed_field(&mut sample, SAMPLE_LOCATION_ID, &ids);
+        packed_field(&mut sample, SAMPLE_VALUE, &values);
+        message_field(&mut out, PROFILE_SAMPLE, &sample);
+    }
+
+    let mut function_ids = HashMap::new();
+    for (id, mapping_id, address) in locations {
+        let mut location = vec![];
+        uint64_field(&mut location, LOCATION_ID, id);
+        uint64_field(&mut location, LOCATION_MAPPING_ID, mapping_id);
+        uint64_field(&mut location, LOCATION_ADDRESS, address);
+        if let Some(symbols) = symbols {
+            for frame in symbols.get(address) {
+                let next_id = function_ids.len() as u64 + 1;
+                let function_id = *function_ids
+                    .entry((&frame.function, &frame.file))
+                    .or_insert_with(|| {
+                        encode_function(&mut out, &mut strings, next_id, frame);
+                        next_id
+                    });
+
+                let mut line = vec![];
+                uint64_field(&mut line, LINE_FUNCTION_ID, function_id);
+                uint64_field(&mut line, LINE_LINE, frame.line.map_or(0, u64::from));
+                message_field(&mut location, LOCATION_LINE, &line);
+            }
+        }
+        message_field(&mut out, PROFILE_LOCATION, &location);
+    }
+
+    let period_type = value_type(&mut strings, "space", "bytes");
+    message_field(&mut out, PROFILE_PERIOD_TYPE, &period_type);
+    uint64_field(&mut out, PROFILE_PERIOD, profile.sample_period);
+    let default_sample_type = strings.get("inuse_space");
+    uint64_field(&mut out, PROFILE_DEFAULT_SAMPLE_TYPE, default_sample_type);
+
+    for s in &strings.strings {
+        bytes_field(&mut out, PROFILE_STRING_TABLE, s.as_bytes());
+    }
+
+    out
+}
+
+fn encode_function(out: &mut Vec<u8>, strings: &mut StringTable, id: u64, frame: &Frame) {
+    let name = strings.get(frame.function.as_ref().map_or("", |s| &**s));
+    let filename = strings.get(frame.file.as_ref().map_or("", |s| &**s));
+
+    let mut function = vec![];
+    uint64_field(&mut function, FUNCTION_ID, id);
+    uint64_field(&mut function, FUNCTION_NAME, name);
+    uint64_field(&mut function, FUNCTION_SYSTEM_NAME, name);
+    uint64_field(&mut function, FUNCTION_FILENAME, filename);
+    message_field(out, PROFILE_FUNCTION, &function);
+}
+
+struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, u64>,
+}
+
+impl StringTable {
+    fn new() -> StringTable {
+        // the first entry must be the empty string
+        let mut table = StringTable {
+            strings: vec![],
+            indices: HashMap::new(),
+        };
+        table.get("");
+        table
+    }
+
+    fn get(&mut self, s: &str) -> u64 {
+        if let Some(&index) = self.indices.get(s) {
+            return index;
+        }
+
+        let index = self.strings.len() as u64;
+        self.strings.push(s.to_string());
+        self.indices.insert(s.to_string(), index);
+        index
+    }
+}
+
+fn value_type(strings: &mut StringTable, type_: &str, unit: &str) -> Vec<u8> {
+    let mut buf = vec![];
+    uint64_field(&mut buf, VALUE_TYPE_TYPE, strings.get(type_));
+    uint64_field(&mut buf, VALUE_TYPE_UNIT, strings.get(unit));
+    buf
+}
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn key(buf: &mut Vec<u8>, field: u32, wire_type: u8) {
+    varint(buf, u64::from(field) << 3 | u64::from(wire_type));
+}
+
+// default values are omitted, as in proto3
+fn uint64_field(buf: &mut Vec<u8>, field: u32, value: u64) {
+    if value != 0 {
+        key(buf, field, 0);
+        varint(buf, value);
+    }
+}
+
+fn bytes_field(buf: &mut Vec<u8>, field: u32, value: &[u8]) {
+    key(buf, field, 2);
+    varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+fn message_field(buf: &mut Vec<u8>, field: u32, message: &[u8]) {
+    bytes_field(buf, field, message);
+}
+
+fn packed_field(buf: &mut Vec<u8>, field: u32, values: &[u64]) {
+    let mut packed = vec![];
+    for &value in values {
+        varint(&mut packed, value);
+    }
+    bytes_field(buf, field, &packed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_varint(buf: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = buf[0];
+            *buf = &buf[1..];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    // a minimal decoder returning the (field, value) pairs of a message
+    fn decode(mut buf: &[u8]) -> Vec<(u32, Result<u64, Vec<u8>>)> {
+        let mut fields = vec![];
+        while !buf.is_empty() {
+            let key = read_varint(&mut buf);
+            let field = (key >> 3) as u32;
+            match key & 7 {
+                0 => fields.push((field, Ok(read_varint(&mut buf)))),
+                2 => {
+                    let len = read_varint(&mut buf) as usize;
+                    fields.push((field, Err(buf[..len].to_vec())));
+                    buf = &buf[len..];
+                }
+                t => panic!("unexpected wire type {}", t),
+            }
+        }
+        fields
+    }
+
+    fn unpack(mut buf: &[u8]) -> Vec<u64> {
+        let mut values = vec![];
+        while !buf.is_empty() {
+            values.push(read_varint(&mut buf));
+        }
+        values
+    }
+
+    fn bytes(value: &Result<u64, Vec<u8>>) -> &[u8] {
+        value.as_ref().unwrap_err()
+    }
+
+    #[test]
+    fn varints() {
+        let mut buf = vec![];
+        varint(&mut buf, 1);
+        varint(&mut buf, 300);
+        assert_eq!(buf, [0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn encode_profile() {
+        let profile = HeapProfile::parse(
+            b"\
+heap_v2/524288
+  t*: 2: 2048 [0: 0]
+@ 0x1010 0x2020 0x1030
+  t*: 2: 2048 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 fe:00 1 /bin/app
+00002000-00003000 rw-p 00000000 00:00 0
+",
+        )
+        .unwrap();
+
+        let fields = decode(&encode(&profile));
+
+        let strings = fields
+            .iter()
+            .filter(|f| f.0 == PROFILE_STRING_TABLE)
+            .map(|f| String::from_utf8(bytes(&f.1).to_vec()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(strings[0], "");
+        assert!(strings.contains(&"inuse_space".to_string()));
+        assert!(strings.contains(&"/bin/app".to_string()));
+
+        let sample_types = fields.iter().filter(|f| f.0 == PROFILE_SAMPLE_TYPE).count();
+        assert_eq!(sample_types, 4);
+
+        let mappings = fields
+            .iter()
+            .filter(|f| f.0 == PROFILE_MAPPING)
+            .collect::<Vec<_>>();
+        assert_eq!(mappings.len(), 1);
+
+        let locations = fields
+            .iter()
+            .filter(|f| f.0 == PROFILE_LOCATION)
+            .map(|f| decode(bytes(&f.1)))
+            .collect::<Vec<_>>();
+        assert_eq!(locations.len(), 3);
+        // caller addresses are adjusted, and only those in the executable mapping are attributed
+        assert_eq!(
+            locations[1],
+            [(LOCATION_ID, Ok(2)), (LOCATION_ADDRESS, Ok(0x201f)),]
+        );
+        assert_eq!(
+            locations[2],
+            [
+                (LOCATION_ID, Ok(3)),
+                (LOCATION_MAPPING_ID, Ok(1)),
+                (LOCATION_ADDRESS, Ok(0x102f)),
+            ]
+        );
+
+        let samples = fields
+            .iter()
+            .filter(|f| f.0 == PROFILE_SAMPLE)
+            .map(|f| decode(bytes(&f.1)))
+            .collect::<Vec<_>>();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0][0].0, SAMPLE_LOCATION_ID);
+        assert_eq!(unpack(bytes(&samples[0][0].1)), [1, 2, 3]);
+        assert_eq!(samples[0][1].0, SAMPLE_VALUE);
+        let values = unpack(bytes(&samples[0][1].1));
+        assert_eq!(values[..2], [0, 0]);
+        assert_eq!(values[2], 1025);
+        assert_eq!(values[3], 1049600);
+    }
+
+    #[test]
+    fn encode_symbols() {
+        let profile = HeapProfile::parse(
+            b"\
+heap_v2/524288
+  t*: 1: 1024 [0: 0]
+@ 0x1010 0x1021
+  t*: 1: 1024 [0: 0]
+",
+        )
+        .unwrap();
+
+        let frame = |function: &str, line| Frame {
+            function: Some(function.to_string()),
+            file: Some("src/main.rs".to_string()),
+            line: Some(line),
+        };
+        let mut symbols = Symbols::new();
+        symbols.insert(0x1010, vec![frame("inner", 3), frame("outer", 10)]);
+        symbols.insert(0x1020, vec![frame("outer", 12)]);
+
+        let fields = decode(&encode_symbolized(&profile, &symbols));
+
+        let functions = fields.iter().filter(|f| f.0 == PROFILE_FUNCTION).count();
+        assert_eq!(functions, 2);
+
+        let locations = fields
+            .iter()
+            .filter(|f| f.0 == PROFILE_LOCATION)
+            .map(|f| decode(bytes(&f.1)))
+            .collect::<Vec<_>>();
+        let lines = |location: &[(u32, Result<u64, Vec<u8>>)]| {
+            location
+                .iter()
+                .filter(|f| f.0 == LOCATION_LINE)
+                .map(|f| decode(bytes(&f.1)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            lines(&locations[0]),
+            [
+                [(LINE_FUNCTION_ID, Ok(1)), (LINE_LINE, Ok(3))],
+                [(LINE_FUNCTION_ID, Ok(2)), (LINE_LINE, Ok(10))],
+            ]
+        );
+        assert_eq!(
+            lines(&locations[1]),
+            [[(LINE_FUNCTION_ID, Ok(2)), (LINE_LINE, Ok(12))]]
+        );
+    }
+}
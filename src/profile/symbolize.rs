@@ -0,0 +1,154 @@
+//! In-process symbolization of heap profiles.
+//!
+//! This module requires the `symbolize` Cargo feature.
+use backtrace;
+use std::fs;
+use std::io;
+use std::os::raw::c_void;
+
+use profile::{parse_mapping, Frame, HeapProfile, MappedLibrary, Symbols};
+
+/// Symbolizes the addresses of a heap profile using the current process's debug information.
+///
+/// The profile does not need to have been taken by the current process, but it must have been
+/// taken by the same binary. Each executable mapping recorded in the profile is matched with the
+/// corresponding mapping in `/proc/self/maps`, which accounts for libraries being loaded at
+/// different addresses. Addresses in libraries which are not loaded by the current process are
+/// not symbolized.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::profile::{symbolize, HeapProfile};
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let dump = jemalloc_ctl::prof::dump_to_vec().unwrap();
+///     let profile = HeapProfile::parse(&dump).unwrap();
+///     let symbols = symbolize::symbolize(&profile).unwrap();
+///
+///     for address in profile.stacks[0].call_addresses() {
+///         for frame in symbols.get(address) {
+///             println!("{:?}", frame.function);
+///         }
+///     }
+/// }
+/// ```
+pub fn symbolize(profile: &HeapProfile) -> io::Result<Symbols> {
+    let maps = fs::read_to_string("/proc/self/maps")?;
+    let current = maps.lines().filter_map(parse_mapping).collect::<Vec<_>>();
+
+    let mut symbols = Symbols::new();
+    for stack in &profile.stacks {
+        for address in stack.call_addresses() {
+            if !symbols.get(address).is_empty() {
+                continue;
+            }
+
+            let relocated = match relocate(profile, &current, address) {
+                Some(relocated) => relocated,
+                None => continue,
+            };
+
+            // resolve treats addresses as return addresses and adjusts them itself, but they've
+            // already been adjusted
+            let mut frames = vec![];
+            backtrace::resolve((relocated + 1) as *mut c_void, |symbol| {
+                frames.push(Frame {
+                    function: symbol.name().map(|n| n.to_string()),
+                    file: symbol.filename().map(|f| f.display().to_string()),
+                    line: symbol.lineno(),
+                });
+            });
+            if !frames.is_empty() {
+                symbols.insert(address, frames);
+            }
+        }
+    }
+
+    Ok(symbols)
+}
+
+// translates an address in the profiled process to the corresponding address in this one
+fn relocate(profile: &HeapProfile, current: &[MappedLibrary], address: u64) -> Option<u64> {
+    let library = match profile.library(address) {
+        Some(library) => library,
+        // without mappings we have to assume the profile was taken by this process
+        None if profile.mapped_libraries.is_empty() => return Some(address),
+        None => return None,
+    };
+
+    let file_offset = address - library.start + library.offset;
+    current
+        .iter()
+        .find(|l| {
+            l.path == library.path
+                && l.is_executable()
+                && l.offset <= file_offset
+                && file_offset < l.offset + (l.end - l.start)
+        })
+        .map(|l| file_offset - l.offset + l.start)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[inline(never)]
+    fn target() {}
+
+    #[test]
+    fn current_process() {
+        let address = target as *const () as usize as u64;
+        let profile = HeapProfile::parse(
+            format!(
+                "heap_v2/524288\n  t*: 1: 1 [0: 0]\n@ {:#x}\n  t*: 1: 1 [0: 0]\n",
+                address
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let symbols = symbolize(&profile).unwrap();
+        let frames = symbols.get(address);
+        assert!(!frames.is_empty());
+        assert!(frames[0].function.as_ref().unwrap().contains("target"));
+    }
+
+    #[test]
+    fn relocated() {
+        let maps = fs::read_to_string("/proc/self/maps").unwrap();
+        let current = maps.lines().filter_map(parse_mapping).collect::<Vec<_>>();
+        let address = target as *const () as usize as u64;
+        let library = current.iter().find(|l| l.contains(address)).unwrap();
+
+        // pretend the profiled process loaded the binary 1MB higher
+        let shift = 1024 * 1024;
+        let profile = HeapProfile::parse(
+            format!(
+                "heap_v2/524288\n  t*: 1: 1 [0: 0]\n@ {:#x}\n  t*: 1: 1 [0: 0]\n\n\
+                 MAPPED_LIBRARIES:\n{:x}-{:x} r-xp {:08x} 00:00 0 {}\n",
+                address + shift,
+                library.start + shift,
+                library.end + shift,
+                library.offset,
+                library.path,
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(relocate(&profile, &current, address + shift), Some(address));
+        let symbols = symbolize(&profile).unwrap();
+        assert!(symbols.get(address + shift)[0]
+            .function
+            .as_ref()
+            .unwrap()
+            .contains("target"));
+    }
+}
@@ -0,0 +1,306 @@
+//! Reconciliation of jemalloc's statistics against the kernel's view of the process.
+//!
+//! Growth in a process's resident set size does not necessarily come from jemalloc - thread
+//! stacks, memory mapped files, and other allocators all contribute to it. This module compares
+//! the values reported in `/proc/self/status` and `/proc/self/smaps_rollup` with jemalloc's own
+//! statistics to determine how much memory jemalloc does not account for.
+use std::fmt;
+use std::fs;
+use std::io;
+
+use epoch;
+use stats;
+
+/// A comparison between the kernel's and jemalloc's accounting of the process's memory.
+///
+/// All values are in bytes.
+#[derive(Copy, Clone, Debug)]
+pub struct Reconciliation {
+    /// The resident set size of the process.
+    ///
+    /// This corresponds to `VmRSS` in `/proc/self/status`.
+    pub vm_rss: u64,
+
+    /// The resident anonymous memory of the process.
+    ///
+    /// This corresponds to `RssAnon` in `/proc/self/status`.
+    pub rss_anon: u64,
+
+    /// Summed memory map statistics, or `None` if the kernel does not provide
+    /// `/proc/self/smaps_rollup`.
+    pub smaps: Option<SmapsRollup>,
+
+    /// The value of [`stats::resident`].
+    ///
+    /// [`stats::resident`]: ../stats/fn.resident.html
+    pub resident: usize,
+
+    /// The value of [`stats::mapped`].
+    ///
+    /// [`stats::mapped`]: ../stats/fn.mapped.html
+    pub mapped: usize,
+
+    /// The value of [`stats::metadata`].
+    ///
+    /// [`stats::metadata`]: ../stats/fn.metadata.html
+    pub metadata: usize,
+}
+
+/// Memory statistics summed over all of the process's mappings.
+///
+/// All values are in bytes.
+#[derive(Copy, Clone, Debug)]
+pub struct SmapsRollup {
+    /// The resident set size.
+    pub rss: u64,
+
+    /// The proportional set size, which divides shared pages between the processes sharing them.
+    pub pss: u64,
+
+    /// The resident anonymous memory.
+    pub anonymous: u64,
+
+    /// The anonymous memory which has been swapped out.
+    pub swap: u64,
+}
+
+impl Reconciliation {
+    /// Returns the number of bytes of resident anonymous memory not accounted for by jemalloc.
+    ///
+    /// jemalloc allocates all of its memory (including metadata) in anonymous mappings, so this is
+    /// an estimate of the anonymous memory allocated outside of jemalloc, by thread stacks or other
+    /// allocators for example. [`stats::resident`] may overestimate the true value, so this is a
+    /// lower bound.
+    ///
+    /// [`stats::resident`]: ../stats/fn.resident.html
+    pub fn unaccounted_anon(&self) -> u64 {
+        self.anon().saturating_sub(self.resident as u64)
+    }
+
+    /// Returns the number of bytes by which resident anonymous memory exceeds [`stats::mapped`].
+    ///
+    /// This is a heuristic. [`stats::mapped`] includes jemalloc's metadata mappings but excludes
+    /// inactive dirty extents, which may still be resident, so a nonzero value does not prove that
+    /// memory was allocated outside of jemalloc. Unlike [`unaccounted_anon`], it does not depend
+    /// on [`stats::resident`].
+    ///
+    /// [`stats::mapped`]: ../stats/fn.mapped.html
+    /// [`stats::resident`]: ../stats/fn.resident.html
+    /// [`unaccounted_anon`]: #method.unaccounted_anon
+    pub fn unmapped_anon(&self) -> u64 {
+        self.anon().saturating_sub(self.mapped as u64)
+    }
+
+    /// Returns the number of bytes by which [`stats::mapped`] exceeds [`stats::resident`].
+    ///
+    /// This is a heuristic for memory which jemalloc has mapped but which is not resident, such as
+    /// purged or untouched pages. jemalloc does not guarantee any ordering between the two
+    /// statistics, so it is only an approximation.
+    ///
+    /// [`stats::mapped`]: ../stats/fn.mapped.html
+    /// [`stats::resident`]: ../stats/fn.resident.html
+    pub fn non_resident(&self) -> u64 {
+        (self.mapped as u64).saturating_sub(self.resident as u64)
+    }
+
+    /// Returns the number of bytes of the resident set not accounted for by jemalloc.
+    ///
+    /// Unlike [`unaccounted_anon`], this includes file-backed memory such as the executable and
+    /// memory mapped files.
+    ///
+    /// [`unaccounted_anon`]: #method.unaccounted_anon
+    pub fn unaccounted_rss(&self) -> u64 {
+        self.rss().saturating_sub(self.resident as u64)
+    }
+
+    // `smaps_rollup` is computed exactly, while `status` values are approximations
+    fn rss(&self) -> u64 {
+        match self.smaps {
+            Some(ref smaps) => smaps.rss,
+            None => self.vm_rss,
+        }
+    }
+
+    fn anon(&self) -> u64 {
+        match self.smaps {
+            Some(ref smaps) => smaps.anonymous,
+            None => self.rss_anon,
+        }
+    }
+}
+
+impl fmt::Display for Reconciliation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "kernel:")?;
+        writeln!(fmt, "  VmRSS:      {:>16}", self.vm_rss)?;
+        writeln!(fmt, "  RssAnon:    {:>16}", self.rss_anon)?;
+        if let Some(ref smaps) = self.smaps {
+            writeln!(fmt, "  Rss:        {:>16}", smaps.rss)?;
+            writeln!(fmt, "  Pss:        {:>16}", smaps.pss)?;
+            writeln!(fmt, "  Anonymous:  {:>16}", smaps.anonymous)?;
+            writeln!(fmt, "  Swap:       {:>16}", smaps.swap)?;
+        }
+        writeln!(fmt, "jemalloc:")?;
+        writeln!(fmt, "  resident:   {:>16}", self.resident)?;
+        writeln!(fmt, "  mapped:     {:>16}", self.mapped)?;
+        writeln!(fmt, "  metadata:   {:>16}", self.metadata)?;
+        writeln!(fmt, "  unresident: {:>16}", self.non_resident())?;
+        writeln!(fmt, "unaccounted:")?;
+        writeln!(fmt, "  anonymous:  {:>16}", self.unaccounted_anon())?;
+        writeln!(fmt, "  unmapped:   {:>16}", self.unmapped_anon())?;
+        write!(fmt, "  total:      {:>16}", self.unaccounted_rss())
+    }
+}
+
+/// Compares the kernel's accounting of the process's memory with jemalloc's.
+///
+/// The jemalloc epoch is advanced before reading statistics so that they are consistent with the
+/// kernel's values.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let reconciliation = jemalloc_ctl::reconcile::reconcile().unwrap();
+///     println!("{}", reconciliation);
+/// }
+/// ```
+pub fn reconcile() -> io::Result<Reconciliation> {
+    epoch()?;
+    let resident = stats::resident()?;
+    let mapped = stats::mapped()?;
+    let metadata = stats::metadata()?;
+
+    let status = fs::read_to_string("/proc/self/status")?;
+    let smaps = match fs::read_to_string("/proc/self/smaps_rollup") {
+        Ok(smaps) => Some(SmapsRollup {
+            rss: field(&smaps, "Rss")?,
+            pss: field(&smaps, "Pss")?,
+            anonymous: field(&smaps, "Anonymous")?,
+            swap: field(&smaps, "Swap")?,
+        }),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    Ok(Reconciliation {
+        vm_rss: field(&status, "VmRSS")?,
+        rss_anon: field(&status, "RssAnon")?,
+        smaps,
+        resident,
+        mapped,
+        metadata,
+    })
+}
+
+// parses a `Name:   1234 kB` line into a byte count
+fn field(s: &str, name: &str) -> io::Result<u64> {
+    for line in s.lines() {
+        let mut parts = line.splitn(2, ':');
+        if parts.next() != Some(name) {
+            continue;
+        }
+
+        let mut value = parts.next().unwrap_or("").split_whitespace();
+        let n = value
+            .next()
+            .unwrap_or("")
+            .parse::<u64>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let scale = match value.next() {
+            Some("kB") => 1024,
+            None => 1,
+            Some(unit) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown unit `{}` for {}", unit, name),
+                ))
+            }
+        };
+        return Ok(n * scale);
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("field {} not found", name),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_fields() {
+        let status = "Name:\tfoo\nVmRSS:\t    1792 kB\nRssAnon:\t     140 kB\nThreads:\t1\n";
+        assert_eq!(field(status, "VmRSS").unwrap(), 1792 * 1024);
+        assert_eq!(field(status, "RssAnon").unwrap(), 140 * 1024);
+        assert_eq!(field(status, "Threads").unwrap(), 1);
+        assert!(field(status, "RssFile").is_err());
+        assert!(field(status, "Name").is_err());
+    }
+
+    #[test]
+    fn report() {
+        let mut reconciliation = Reconciliation {
+            vm_rss: 10_000,
+            rss_anon: 6_000,
+            smaps: None,
+            resident: 3_000,
+            mapped: 3_500,
+            metadata: 500,
+        };
+        assert_eq!(reconciliation.unaccounted_anon(), 3_000);
+        assert_eq!(reconciliation.unmapped_anon(), 2_500);
+        assert_eq!(reconciliation.non_resident(), 500);
+        assert_eq!(reconciliation.unaccounted_rss(), 7_000);
+        assert_eq!(
+            reconciliation.to_string(),
+            "\
+kernel:
+  VmRSS:                 10000
+  RssAnon:                6000
+jemalloc:
+  resident:               3000
+  mapped:                 3500
+  metadata:                500
+  unresident:              500
+unaccounted:
+  anonymous:              3000
+  unmapped:               2500
+  total:                  7000"
+        );
+
+        reconciliation.smaps = Some(SmapsRollup {
+            rss: 12_000,
+            pss: 11_000,
+            anonymous: 7_000,
+            swap: 0,
+        });
+        assert_eq!(reconciliation.unaccounted_anon(), 4_000);
+        assert_eq!(reconciliation.unmapped_anon(), 3_500);
+        assert_eq!(reconciliation.unaccounted_rss(), 9_000);
+        let rss = format!("\n  Rss:        {:>16}\n", 12_000);
+        assert!(reconciliation.to_string().contains(&rss));
+    }
+
+    #[test]
+    fn basic() {
+        let reconciliation = reconcile().unwrap();
+        assert!(reconciliation.vm_rss >= reconciliation.rss_anon);
+
+        let report = reconciliation.to_string();
+        assert!(report.starts_with("kernel:\n  VmRSS:"));
+        assert!(report.contains(&format!(
+            "\n  resident:   {:>16}\n",
+            reconciliation.resident
+        )));
+        assert!(report.contains("\nunaccounted:\n"));
+    }
+}
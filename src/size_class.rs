@@ -0,0 +1,176 @@
+//! Size class rounding.
+//!
+//! jemalloc rounds every allocation up to one of a fixed set of size classes, so a buffer's
+//! allocation is frequently larger than the capacity it was requested with. These helpers compute
+//! the size class a request lands in, allowing buffers to be sized to use that space rather than
+//! wasting it.
+//!
+//! The helpers are only meaningful when jemalloc is the global allocator.
+use jemalloc_sys;
+use std::mem;
+
+use layout_to_flags;
+
+/// Returns the usable size of an allocation of `size` bytes.
+///
+/// This is the size of the size class that an allocation of `size` bytes lands in. If `size` is
+/// too large to be allocated, it is returned unchanged.
+///
+/// This corresponds to `nallocx`.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     assert!(jemalloc_ctl::size_class::good_size(100) >= 100);
+/// }
+/// ```
+pub fn good_size(size: usize) -> usize {
+    good_size_aligned(size, 1)
+}
+
+fn good_size_aligned(size: usize, align: usize) -> usize {
+    if size == 0 {
+        return 0;
+    }
+
+    match unsafe { jemalloc_sys::nallocx(size, layout_to_flags(align, size)) } {
+        0 => size,
+        usable => usable,
+    }
+}
+
+/// Returns the number of `T`s which fit in the size class of an allocation of `capacity` `T`s.
+///
+/// This is at least `capacity`.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let capacity = jemalloc_ctl::size_class::round_capacity::<u64>(13);
+///     let buf = Vec::<u64>::with_capacity(capacity);
+///     assert!(buf.capacity() >= 13);
+/// }
+/// ```
+pub fn round_capacity<T>(capacity: usize) -> usize {
+    let size = mem::size_of::<T>();
+    if size == 0 {
+        return capacity;
+    }
+
+    match capacity.checked_mul(size) {
+        Some(bytes) => good_size_aligned(bytes, mem::align_of::<T>()) / size,
+        None => capacity,
+    }
+}
+
+/// An extension trait for buffers which reserves capacity up to a size class boundary.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::size_class::{self, ReserveSizeClass};
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let mut buf = Vec::<u8>::new();
+///     buf.reserve_size_class(100);
+///     assert_eq!(buf.capacity(), size_class::good_size(100));
+/// }
+/// ```
+pub trait ReserveSizeClass {
+    /// Reserves capacity for at least `additional` more elements, extending the capacity to fill
+    /// the size class of the resulting allocation.
+    ///
+    /// Unlike `reserve`, this does not speculatively over-allocate, but does not waste the space
+    /// which jemalloc's rounding would have added anyway. Nothing happens if the capacity is
+    /// already sufficient.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the new capacity overflows `usize`.
+    fn reserve_size_class(&mut self, additional: usize);
+}
+
+impl<T> ReserveSizeClass for Vec<T> {
+    fn reserve_size_class(&mut self, additional: usize) {
+        let required = self
+            .len()
+            .checked_add(additional)
+            .expect("capacity overflow");
+        if required <= self.capacity() {
+            return;
+        }
+
+        let capacity = round_capacity::<T>(required);
+        self.reserve_exact(capacity - self.len());
+    }
+}
+
+impl ReserveSizeClass for String {
+    fn reserve_size_class(&mut self, additional: usize) {
+        let required = self
+            .len()
+            .checked_add(additional)
+            .expect("capacity overflow");
+        if required <= self.capacity() {
+            return;
+        }
+
+        let capacity = good_size(required);
+        self.reserve_exact(capacity - self.len());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rounding() {
+        assert_eq!(good_size(0), 0);
+        assert_eq!(good_size(100), 112);
+        assert_eq!(good_size(4096), 4096);
+        assert_eq!(good_size(usize::MAX), usize::MAX);
+
+        assert_eq!(round_capacity::<u64>(0), 0);
+        assert_eq!(round_capacity::<u64>(13), 14);
+        assert_eq!(round_capacity::<[u8; 3]>(33), 37);
+        assert_eq!(round_capacity::<()>(7), 7);
+        assert_eq!(round_capacity::<u64>(usize::MAX), usize::MAX);
+    }
+
+    #[test]
+    fn reserve() {
+        let mut buf = vec![1u64; 3];
+        buf.reserve_size_class(10);
+        assert_eq!(buf.capacity(), 14);
+        assert_eq!(buf, [1, 1, 1]);
+
+        buf.reserve_size_class(1);
+        assert_eq!(buf.capacity(), 14);
+
+        let mut s = String::from("hello");
+        s.reserve_size_class(95);
+        assert_eq!(s.capacity(), 112);
+        assert_eq!(s, "hello");
+    }
+}
@@ -0,0 +1,202 @@
+//! Explicit thread cache management.
+//!
+//! jemalloc normally serves small allocations from an implicit cache owned by each thread (see
+//! [`thread::tcache_enabled`]). This module provides explicitly managed caches which are owned by
+//! the program rather than a thread, and allocators which use an explicit cache or bypass caching
+//! altogether.
+//!
+//! [`thread::tcache_enabled`]: ../thread/fn.tcache_enabled.html
+use jemalloc_sys;
+use libc::{c_char, c_int, c_uint, c_void};
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::io;
+use std::marker::PhantomData;
+
+use {get, layout_to_flags, set};
+
+const CREATE: *const c_char = b"tcache.create\0" as *const _ as *const _;
+const FLUSH: *const c_char = b"tcache.flush\0" as *const _ as *const _;
+const DESTROY: *const c_char = b"tcache.destroy\0" as *const _ as *const _;
+
+/// An explicitly managed thread cache.
+///
+/// The cache is destroyed when the `Tcache` is dropped, which returns its cached memory to the
+/// arenas. Memory allocated through the cache can be freed through any cache or allocator, and
+/// remains valid after the cache is destroyed.
+///
+/// A cache can be used by any thread, but not by multiple threads at once, so the type is `Send`
+/// but not `Sync`.
+///
+/// `Tcache` implements `GlobalAlloc`, passing `MALLOCX_TCACHE` with the cache's identifier to
+/// jemalloc.
+///
+/// This corresponds to `tcache.create`, `tcache.flush`, and `tcache.destroy` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::tcache::Tcache;
+/// use std::alloc::{GlobalAlloc, Layout};
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let tcache = Tcache::new().unwrap();
+///
+///     let layout = Layout::from_size_align(64, 8).unwrap();
+///     unsafe {
+///         let ptr = tcache.alloc(layout);
+///         assert!(!ptr.is_null());
+///         tcache.dealloc(ptr, layout);
+///     }
+///
+///     tcache.flush().unwrap();
+/// }
+/// ```
+pub struct Tcache {
+    id: c_uint,
+    _p: PhantomData<Cell<()>>,
+}
+
+impl Tcache {
+    /// Creates a new explicit thread cache.
+    pub fn new() -> io::Result<Tcache> {
+        let id = unsafe { get(CREATE)? };
+        Ok(Tcache {
+            id,
+            _p: PhantomData,
+        })
+    }
+
+    /// Returns the cache's identifier.
+    pub fn id(&self) -> c_uint {
+        self.id
+    }
+
+    /// Returns all memory cached by the cache to the arenas.
+    pub fn flush(&self) -> io::Result<()> {
+        unsafe { set(FLUSH, self.id) }
+    }
+
+    fn flags(&self, layout: &Layout) -> c_int {
+        layout_to_flags(layout.align(), layout.size())
+            | jemalloc_sys::MALLOCX_TCACHE(self.id as usize)
+    }
+}
+
+impl Drop for Tcache {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = set(DESTROY, self.id);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Tcache {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        jemalloc_sys::mallocx(layout.size(), self.flags(&layout)) as *mut u8
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let flags = self.flags(&layout) | jemalloc_sys::MALLOCX_ZERO;
+        jemalloc_sys::mallocx(layout.size(), flags) as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        jemalloc_sys::sdallocx(ptr as *mut c_void, layout.size(), self.flags(&layout))
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let flags = self.flags(&Layout::from_size_align_unchecked(new_size, layout.align()));
+        jemalloc_sys::rallocx(ptr as *mut c_void, new_size, flags) as *mut u8
+    }
+}
+
+/// An allocator which bypasses thread caches.
+///
+/// Every allocation and deallocation goes directly to an arena, passing `MALLOCX_TCACHE_NONE` to
+/// jemalloc. This avoids memory being held in thread caches at the cost of synchronization on
+/// each operation.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::tcache::Uncached;
+///
+/// #[global_allocator]
+/// static ALLOC: Uncached = Uncached;
+///
+/// fn main() {
+///     let buf = vec![0u8; 64];
+///     assert_eq!(buf.len(), 64);
+/// }
+/// ```
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Uncached;
+
+fn uncached_flags(layout: &Layout) -> c_int {
+    layout_to_flags(layout.align(), layout.size()) | jemalloc_sys::MALLOCX_TCACHE_NONE()
+}
+
+unsafe impl GlobalAlloc for Uncached {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        jemalloc_sys::mallocx(layout.size(), uncached_flags(&layout)) as *mut u8
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let flags = uncached_flags(&layout) | jemalloc_sys::MALLOCX_ZERO;
+        jemalloc_sys::mallocx(layout.size(), flags) as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        jemalloc_sys::sdallocx(ptr as *mut c_void, layout.size(), uncached_flags(&layout))
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let flags = uncached_flags(&Layout::from_size_align_unchecked(new_size, layout.align()));
+        jemalloc_sys::rallocx(ptr as *mut c_void, new_size, flags) as *mut u8
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn explicit_cache() {
+        let a = Tcache::new().unwrap();
+        let b = Tcache::new().unwrap();
+        assert_ne!(a.id(), b.id());
+
+        unsafe {
+            let layout = Layout::from_size_align(48, 64).unwrap();
+            let ptr = a.alloc_zeroed(layout);
+            assert_eq!(ptr as usize % 64, 0);
+            assert!((0..48).all(|i| *ptr.add(i) == 0));
+            let ptr = a.realloc(ptr, layout, 4096);
+            assert_eq!(ptr as usize % 64, 0);
+            // memory can be freed through a different cache
+            b.dealloc(ptr, Layout::from_size_align(4096, 64).unwrap());
+        }
+
+        a.flush().unwrap();
+        b.flush().unwrap();
+    }
+
+    #[test]
+    fn uncached() {
+        unsafe {
+            let layout = Layout::from_size_align(16, 8).unwrap();
+            let ptr = Uncached.alloc(layout);
+            assert!(!ptr.is_null());
+            Uncached.dealloc(ptr, layout);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Allocation assertions for tests.
+//!
+//! These helpers are built on the per-thread counters in the [`thread`] module, and only count
+//! allocations made by the current thread. The [`assert_no_alloc!`] and
+//! [`assert_allocates_at_most!`] macros behave like their function counterparts, but include the
+//! measured expression in the panic message.
+//!
+//! [`thread`]: ../thread/index.html
+//! [`assert_no_alloc!`]: ../macro.assert_no_alloc.html
+//! [`assert_allocates_at_most!`]: ../macro.assert_allocates_at_most.html
+
+use thread;
+
+/// Runs a closure, panicking if it allocates any memory on the current thread.
+///
+/// The closure's result is returned.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::testing::assert_no_alloc;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let mut buf = Vec::with_capacity(16);
+///     assert_no_alloc(|| buf.extend_from_slice(b"hello world"));
+/// }
+/// ```
+#[track_caller]
+pub fn assert_no_alloc<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    assert_allocates_at_most(0, f)
+}
+
+/// Runs a closure, panicking if it allocates more than `max` bytes on the current thread.
+///
+/// Only allocated bytes are counted; memory freed by the closure does not offset them. The
+/// closure's result is returned.
+///
+/// # Examples
+///
+/// ```should_panic
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::testing::assert_allocates_at_most;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     assert_allocates_at_most(1024, || vec![0u8; 4096]);
+/// }
+/// ```
+#[track_caller]
+pub fn assert_allocates_at_most<F, T>(max: u64, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let (value, allocations) = thread::measure(f).expect("error reading allocation counters");
+    if allocations.allocated > max {
+        panic!(
+            "closure allocated {} bytes, expected at most {}",
+            allocations.allocated, max
+        );
+    }
+    value
+}
+
+/// Evaluates an expression, panicking if it allocates any memory on the current thread.
+///
+/// The expression is evaluated inside of a closure, so `return` and `?` apply to that closure
+/// rather than the enclosing function. The value of the expression is returned.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate jemalloc_ctl;
+/// extern crate jemallocator;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let buf = [1, 2, 3];
+///     let sum = assert_no_alloc!(buf.iter().sum::<i32>());
+///     assert_eq!(sum, 6);
+/// }
+/// ```
+#[macro_export]
+macro_rules! assert_no_alloc {
+    ($e:expr) => {
+        $crate::assert_allocates_at_most!(0, $e)
+    };
+}
+
+/// Evaluates an expression, panicking if it allocates more than the specified number of bytes on
+/// the current thread.
+///
+/// The expression is evaluated inside of a closure, so `return` and `?` apply to that closure
+/// rather than the enclosing function. The value of the expression is returned.
+///
+/// # Examples
+///
+/// ```
+/// #[macro_use]
+/// extern crate jemalloc_ctl;
+/// extern crate jemallocator;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let buf = assert_allocates_at_most!(8192, vec![0u8; 4096]);
+///     assert_eq!(buf.len(), 4096);
+/// }
+/// ```
+#[macro_export]
+macro_rules! assert_allocates_at_most {
+    ($max:expr, $e:expr) => {{
+        let max: u64 = $max;
+        let (value, allocations) =
+            $crate::thread::measure(|| $e).expect("error reading allocation counters");
+        if allocations.allocated > max {
+            panic!(
+                "`{}` allocated {} bytes, expected at most {}",
+                stringify!($e),
+                allocations.allocated,
+                max
+            );
+        }
+        value
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "`vec![0u8; 4096]` allocated 4096 bytes, expected at most 0")]
+    fn macro_message() {
+        assert_no_alloc!(vec![0u8; 4096]);
+    }
+
+    #[test]
+    fn no_alloc() {
+        let buf = [1, 2, 3];
+        assert_eq!(assert_no_alloc(|| buf.len()), 3);
+    }
+}
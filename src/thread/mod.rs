@@ -0,0 +1,1345 @@
+//! Thread specific operations.
+use libc;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::c_char;
+use std::sync::OnceLock;
+use std::thread;
+
+use {call, call_mib, get, get_mib, name_to_mib, set, set_mib};
+
+pub mod registry;
+
+const ALLOCATEDP: *const c_char = b"thread.allocatedp\0" as *const _ as *const _;
+
+/// Returns a thread-local pointer to the total number of bytes allocated by the current thread.
+///
+/// Unlike [`stats::allocated`], the value returned by this type is not the number of bytes
+/// *currently* allocated, but rather the number of bytes that have *ever* been allocated by this
+/// thread.
+///
+/// This function doesn't return the value directly, but actually a pointer to the value. This
+/// allows for very fast repeated lookup, since there is no function call overhead. The pointer type
+/// cannot be sent to other threads, but `allocated` can be called on different threads and will
+/// return the appropriate pointer for each of them.
+///
+/// This corresponds to `thread.allocatedp` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let allocated = jemalloc_ctl::thread::allocatedp().unwrap();
+///
+///     let a = allocated.get();
+///     let buf = vec![0; 1024 * 1024];
+///     let b = allocated.get();
+///     drop(buf);
+///     let c = allocated.get();
+///
+///     assert!(a < b);
+///     assert_eq!(b, c);
+/// }
+/// ```
+pub fn allocatedp() -> io::Result<ThreadLocal<u64>> {
+    unsafe { get(ALLOCATEDP).map(ThreadLocal) }
+}
+
+/// A type providing access to the total number of bytes allocated by the current thread.
+///
+/// Unlike [`stats::Allocated`], the value returned by this type is not the number of bytes
+/// *currently* allocated, but rather the number of bytes that have *ever* been allocated by this
+/// thread.
+///
+/// The `get` method doesn't return the value directly, but actually a pointer to the value. This
+/// allows for very fast repeated lookup, since there is no function call overhead. The pointer type
+/// cannot be sent to other threads, but `Allocated::get` can be called on different threads and
+/// will return the appropriate pointer for each of them.
+///
+/// # Example
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::AllocatedP;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let allocated = AllocatedP::new().unwrap();
+///     let allocated = allocated.get().unwrap();
+///
+///     let a = allocated.get();
+///     let buf = vec![0; 1024 * 1024];
+///     let b = allocated.get();
+///     drop(buf);
+///     let c = allocated.get();
+///
+///     assert!(a < b);
+///     assert_eq!(b, c);
+/// }
+/// ```
+///
+/// [`stats::Allocated`]: ../stats/struct.Allocated.html
+#[derive(Copy, Clone)]
+pub struct AllocatedP([usize; 2]);
+
+impl AllocatedP {
+    /// Returns a new `Allocated`.
+    pub fn new() -> io::Result<AllocatedP> {
+        let mut mib = [0; 2];
+        unsafe {
+            name_to_mib(ALLOCATEDP, &mut mib)?;
+        }
+        Ok(AllocatedP(mib))
+    }
+
+    /// Returns a thread-local pointer to the total number of bytes allocated by this thread.
+    pub fn get(&self) -> io::Result<ThreadLocal<u64>> {
+        unsafe { get_mib(&self.0).map(ThreadLocal) }
+    }
+}
+
+const DEALLOCATEDP: *const c_char = b"thread.deallocatedp\0" as *const _ as *const _;
+
+/// Returns a pointer to the total number of bytes deallocated by the current thread.
+///
+/// This function doesn't return the value directly, but actually a pointer to the value. This
+/// allows for very fast repeated lookup, since there is no function call overhead. The pointer type
+/// cannot be sent to other threads, but `deallocatedp` can be called on different threads and will
+/// return the appropriate pointer for each of them.
+///
+/// This corresponds to `thread.deallocatedp` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let deallocated = jemalloc_ctl::thread::deallocatedp().unwrap();
+///
+///     let a = deallocated.get();
+///     let buf = vec![0; 1024 * 1024];
+///     let b = deallocated.get();
+///     drop(buf);
+///     let c = deallocated.get();
+///
+///     assert_eq!(a, b);
+///     assert!(b < c);
+/// }
+/// ```
+pub fn deallocatedp() -> io::Result<ThreadLocal<u64>> {
+    unsafe { get(DEALLOCATEDP).map(ThreadLocal) }
+}
+
+/// A type providing access to the total number of bytes deallocated by the current thread.
+///
+/// The `get` method doesn't return the value directly, but actually a pointer to the value. This
+/// allows for very fast repeated lookup, since there is no function call overhead. The pointer type
+/// cannot be sent to other threads, but `DeallocatedP::get` can be called on different threads and
+/// will return the appropriate pointer for each of them.
+///
+/// # Example
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::DeallocatedP;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let deallocated = DeallocatedP::new().unwrap();
+///     let deallocated = deallocated.get().unwrap();
+///
+///     let a = deallocated.get();
+///     let buf = vec![0; 1024 * 1024];
+///     let b = deallocated.get();
+///     drop(buf);
+///     let c = deallocated.get();
+///
+///     assert_eq!(a, b);
+///     assert!(b < c);
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct DeallocatedP([usize; 2]);
+
+impl DeallocatedP {
+    /// Returns a new `Deallocated`.
+    pub fn new() -> io::Result<DeallocatedP> {
+        let mut mib = [0; 2];
+        unsafe {
+            name_to_mib(DEALLOCATEDP, &mut mib)?;
+        }
+        Ok(DeallocatedP(mib))
+    }
+
+    /// Returns a thread-local pointer to the total number of bytes deallocated by this thread.
+    pub fn get(&self) -> io::Result<ThreadLocal<u64>> {
+        unsafe {
+            let ptr = get_mib::<*mut u64>(&self.0)?;
+            Ok(ThreadLocal(ptr))
+        }
+    }
+}
+
+const ALLOCATED: *const c_char = b"thread.allocated\0" as *const _ as *const _;
+
+/// Returns the total number of bytes allocated by the current thread.
+///
+/// Unlike [`stats::allocated`], the value returned is not the number of bytes *currently*
+/// allocated, but rather the number of bytes that have *ever* been allocated by this thread.
+///
+/// [`allocatedp`] is faster when the value is read repeatedly.
+///
+/// This corresponds to `thread.allocated` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let a = jemalloc_ctl::thread::allocated().unwrap();
+///     let buf = vec![0; 1024 * 1024];
+///     let b = jemalloc_ctl::thread::allocated().unwrap();
+///     drop(buf);
+///
+///     assert!(a < b);
+/// }
+/// ```
+///
+/// [`stats::allocated`]: ../stats/fn.allocated.html
+/// [`allocatedp`]: fn.allocatedp.html
+pub fn allocated() -> io::Result<u64> {
+    unsafe { get(ALLOCATED) }
+}
+
+/// A type providing access to the total number of bytes allocated by the current thread.
+///
+/// This corresponds to `thread.allocated` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::Allocated;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let allocated = Allocated::new().unwrap();
+///
+///     let a = allocated.get().unwrap();
+///     let buf = vec![0; 1024 * 1024];
+///     let b = allocated.get().unwrap();
+///     drop(buf);
+///
+///     assert!(a < b);
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct Allocated([usize; 2]);
+
+impl Allocated {
+    /// Returns a new `Allocated`.
+    pub fn new() -> io::Result<Allocated> {
+        let mut mib = [0; 2];
+        unsafe {
+            name_to_mib(ALLOCATED, &mut mib)?;
+        }
+        Ok(Allocated(mib))
+    }
+
+    /// Returns the total number of bytes allocated by the current thread.
+    pub fn get(&self) -> io::Result<u64> {
+        unsafe { get_mib(&self.0) }
+    }
+}
+
+const DEALLOCATED: *const c_char = b"thread.deallocated\0" as *const _ as *const _;
+
+/// Returns the total number of bytes deallocated by the current thread.
+///
+/// [`deallocatedp`] is faster when the value is read repeatedly.
+///
+/// This corresponds to `thread.deallocated` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let a = jemalloc_ctl::thread::deallocated().unwrap();
+///     let buf = vec![0; 1024 * 1024];
+///     drop(buf);
+///     let b = jemalloc_ctl::thread::deallocated().unwrap();
+///
+///     assert!(a < b);
+/// }
+/// ```
+///
+/// [`deallocatedp`]: fn.deallocatedp.html
+pub fn deallocated() -> io::Result<u64> {
+    unsafe { get(DEALLOCATED) }
+}
+
+/// A type providing access to the total number of bytes deallocated by the current thread.
+///
+/// This corresponds to `thread.deallocated` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::Deallocated;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let deallocated = Deallocated::new().unwrap();
+///
+///     let a = deallocated.get().unwrap();
+///     let buf = vec![0; 1024 * 1024];
+///     drop(buf);
+///     let b = deallocated.get().unwrap();
+///
+///     assert!(a < b);
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct Deallocated([usize; 2]);
+
+impl Deallocated {
+    /// Returns a new `Deallocated`.
+    pub fn new() -> io::Result<Deallocated> {
+        let mut mib = [0; 2];
+        unsafe {
+            name_to_mib(DEALLOCATED, &mut mib)?;
+        }
+        Ok(Deallocated(mib))
+    }
+
+    /// Returns the total number of bytes deallocated by the current thread.
+    pub fn get(&self) -> io::Result<u64> {
+        unsafe { get_mib(&self.0) }
+    }
+}
+
+const PROF_ACTIVE: *const c_char = b"thread.prof.active\0" as *const _ as *const _;
+
+/// Returns whether allocations made by the current thread are sampled for heap profiling.
+///
+/// Allocations are only sampled if this and [`prof::active`] are both set. It is initialized by
+/// the `prof_thread_active_init` option.
+///
+/// This corresponds to `thread.prof.active` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     println!("thread profiling active: {}", jemalloc_ctl::thread::prof_active().unwrap());
+/// }
+/// ```
+///
+/// [`prof::active`]: ../prof/fn.active.html
+pub fn prof_active() -> io::Result<bool> {
+    unsafe { get(PROF_ACTIVE) }
+}
+
+/// Activates or deactivates sampling of allocations made by the current thread.
+///
+/// This corresponds to `thread.prof.active` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::thread::set_prof_active(false).unwrap();
+///     assert!(!jemalloc_ctl::thread::prof_active().unwrap());
+/// }
+/// ```
+pub fn set_prof_active(active: bool) -> io::Result<()> {
+    unsafe { set(PROF_ACTIVE, active) }
+}
+
+/// A type providing access to whether allocations made by the current thread are sampled for
+/// heap profiling.
+///
+/// This corresponds to `thread.prof.active` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::ProfActive;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let active = ProfActive::new().unwrap();
+///     active.set(true).unwrap();
+///     assert!(active.get().unwrap());
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct ProfActive([usize; 3]);
+
+impl ProfActive {
+    /// Returns a new `ProfActive`.
+    pub fn new() -> io::Result<ProfActive> {
+        let mut mib = [0; 3];
+        unsafe {
+            name_to_mib(PROF_ACTIVE, &mut mib)?;
+        }
+        Ok(ProfActive(mib))
+    }
+
+    /// Returns whether allocations made by the current thread are sampled.
+    pub fn get(&self) -> io::Result<bool> {
+        unsafe { get_mib(&self.0) }
+    }
+
+    /// Activates or deactivates sampling of allocations made by the current thread.
+    pub fn set(&self, active: bool) -> io::Result<()> {
+        unsafe { set_mib(&self.0, active) }
+    }
+}
+
+const PROF_NAME: *const c_char = b"thread.prof.name\0" as *const _ as *const _;
+
+/// Returns the name associated with the current thread in heap profiles.
+///
+/// An empty string is returned if the thread has not been named.
+///
+/// This corresponds to `thread.prof.name` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     println!("thread profiling name: {}", jemalloc_ctl::thread::prof_name().unwrap());
+/// }
+/// ```
+pub fn prof_name() -> io::Result<String> {
+    unsafe { get(PROF_NAME).and_then(|ptr| owned_str(ptr)) }
+}
+
+/// Sets the name associated with the current thread in heap profiles.
+///
+/// The name may only contain printable ASCII characters, spaces, and tabs.
+///
+/// This corresponds to `thread.prof.name` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use std::ffi::CString;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let name = CString::new("worker").unwrap();
+///     jemalloc_ctl::thread::set_prof_name(&name).unwrap();
+///     assert_eq!(jemalloc_ctl::thread::prof_name().unwrap(), "worker");
+/// }
+/// ```
+pub fn set_prof_name(name: &CStr) -> io::Result<()> {
+    unsafe { set(PROF_NAME, name.as_ptr()) }
+}
+
+/// Names the current thread in heap profiles after its Rust name.
+///
+/// Characters jemalloc does not allow in names are replaced with `_`. Nothing is done if the
+/// thread does not have a name.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use std::thread;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     thread::Builder::new()
+///         .name("worker-1".to_string())
+///         .spawn(|| {
+///             jemalloc_ctl::thread::set_prof_name_from_current().unwrap();
+///             // ...
+///         })
+///         .unwrap()
+///         .join()
+///         .unwrap();
+/// }
+/// ```
+pub fn set_prof_name_from_current() -> io::Result<()> {
+    match thread::current().name() {
+        Some(name) => set_prof_name(&sanitize_name(name)),
+        None => Ok(()),
+    }
+}
+
+/// A type providing access to the name associated with the current thread in heap profiles.
+///
+/// This corresponds to `thread.prof.name` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::ProfName;
+/// use std::ffi::CString;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let prof_name = ProfName::new().unwrap();
+///
+///     let name = CString::new("worker").unwrap();
+///     prof_name.set(&name).unwrap();
+///     assert_eq!(prof_name.get().unwrap(), "worker");
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct ProfName([usize; 3]);
+
+impl ProfName {
+    /// Returns a new `ProfName`.
+    pub fn new() -> io::Result<ProfName> {
+        let mut mib = [0; 3];
+        unsafe {
+            name_to_mib(PROF_NAME, &mut mib)?;
+        }
+        Ok(ProfName(mib))
+    }
+
+    /// Returns the name associated with the current thread in heap profiles.
+    pub fn get(&self) -> io::Result<String> {
+        unsafe { get_mib(&self.0).and_then(|ptr| owned_str(ptr)) }
+    }
+
+    /// Sets the name associated with the current thread in heap profiles.
+    pub fn set(&self, name: &CStr) -> io::Result<()> {
+        unsafe { set_mib(&self.0, name.as_ptr()) }
+    }
+}
+
+// the name is owned by jemalloc and freed if it's changed, so it has to be copied out
+unsafe fn owned_str(ptr: *const c_char) -> io::Result<String> {
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map(|s| s.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// jemalloc only allows characters matching isgraph or isblank
+fn sanitize_name(name: &str) -> CString {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() || c == ' ' || c == '\t' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    // all NUL bytes were replaced
+    CString::new(name).unwrap()
+}
+
+const TCACHE_ENABLED: *const c_char = b"thread.tcache.enabled\0" as *const _ as *const _;
+
+/// Returns whether the current thread's cache is enabled.
+///
+/// This corresponds to `thread.tcache.enabled` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     println!("thread cache enabled: {}", jemalloc_ctl::thread::tcache_enabled().unwrap());
+/// }
+/// ```
+pub fn tcache_enabled() -> io::Result<bool> {
+    unsafe { get(TCACHE_ENABLED) }
+}
+
+/// Enables or disables the current thread's cache.
+///
+/// Disabling the cache flushes it.
+///
+/// This corresponds to `thread.tcache.enabled` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::thread::set_tcache_enabled(false).unwrap();
+///     assert!(!jemalloc_ctl::thread::tcache_enabled().unwrap());
+/// }
+/// ```
+pub fn set_tcache_enabled(enabled: bool) -> io::Result<()> {
+    unsafe { set(TCACHE_ENABLED, enabled) }
+}
+
+/// A type providing access to whether the current thread's cache is enabled.
+///
+/// This corresponds to `thread.tcache.enabled` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::TcacheEnabled;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let enabled = TcacheEnabled::new().unwrap();
+///     enabled.set(false).unwrap();
+///     assert!(!enabled.get().unwrap());
+///     enabled.set(true).unwrap();
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct TcacheEnabled([usize; 3]);
+
+impl TcacheEnabled {
+    /// Returns a new `TcacheEnabled`.
+    pub fn new() -> io::Result<TcacheEnabled> {
+        let mut mib = [0; 3];
+        unsafe {
+            name_to_mib(TCACHE_ENABLED, &mut mib)?;
+        }
+        Ok(TcacheEnabled(mib))
+    }
+
+    /// Returns whether the current thread's cache is enabled.
+    pub fn get(&self) -> io::Result<bool> {
+        unsafe { get_mib(&self.0) }
+    }
+
+    /// Enables or disables the current thread's cache.
+    pub fn set(&self, enabled: bool) -> io::Result<()> {
+        unsafe { set_mib(&self.0, enabled) }
+    }
+}
+
+const TCACHE_FLUSH: *const c_char = b"thread.tcache.flush\0" as *const _ as *const _;
+
+/// Flushes the current thread's cache, returning its cached memory to the arenas.
+///
+/// This is useful for threads which are about to go idle for a long time, as memory in their
+/// cache cannot otherwise be reused by other threads or purged.
+///
+/// This corresponds to `thread.tcache.flush` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::thread::tcache_flush().unwrap();
+/// }
+/// ```
+pub fn tcache_flush() -> io::Result<()> {
+    unsafe { call(TCACHE_FLUSH) }
+}
+
+/// A type providing the ability to flush the current thread's cache.
+///
+/// This corresponds to `thread.tcache.flush` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::TcacheFlush;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let flush = TcacheFlush::new().unwrap();
+///     flush.flush().unwrap();
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct TcacheFlush([usize; 3]);
+
+impl TcacheFlush {
+    /// Returns a new `TcacheFlush`.
+    pub fn new() -> io::Result<TcacheFlush> {
+        let mut mib = [0; 3];
+        unsafe {
+            name_to_mib(TCACHE_FLUSH, &mut mib)?;
+        }
+        Ok(TcacheFlush(mib))
+    }
+
+    /// Flushes the current thread's cache.
+    pub fn flush(&self) -> io::Result<()> {
+        unsafe { call_mib(&self.0) }
+    }
+}
+
+const IDLE: *const c_char = b"thread.idle\0" as *const _ as *const _;
+
+/// Hints to jemalloc that the current thread is about to go idle.
+///
+/// jemalloc flushes the thread's cache and may purge unused memory from its arena. This was
+/// added in jemalloc 5.2.1; an error with the `ENOENT` OS error code is returned by older
+/// versions. See [`on_park`] for a version which falls back to flushing the thread's cache.
+///
+/// This corresponds to `thread.idle` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::thread::idle().unwrap();
+/// }
+/// ```
+///
+/// [`on_park`]: fn.on_park.html
+pub fn idle() -> io::Result<()> {
+    unsafe { call(IDLE) }
+}
+
+/// A type providing the ability to hint that the current thread is about to go idle.
+///
+/// This corresponds to `thread.idle` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::Idle;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let idle = Idle::new().unwrap();
+///     idle.idle().unwrap();
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct Idle([usize; 2]);
+
+impl Idle {
+    /// Returns a new `Idle`.
+    pub fn new() -> io::Result<Idle> {
+        let mut mib = [0; 2];
+        unsafe {
+            name_to_mib(IDLE, &mut mib)?;
+        }
+        Ok(Idle(mib))
+    }
+
+    /// Hints to jemalloc that the current thread is about to go idle.
+    pub fn idle(&self) -> io::Result<()> {
+        unsafe { call_mib(&self.0) }
+    }
+}
+
+/// Releases the current thread's cached memory before it parks.
+///
+/// This is intended to be registered as the park callback of a thread pool so that idle workers
+/// do not hold on to memory. It calls [`idle`] if the jemalloc version supports it, and
+/// [`tcache_flush`] otherwise. Errors are ignored.
+///
+/// Refilling a flushed cache has a cost, so pools whose workers park very frequently may want to
+/// only call this after a worker has been idle for a while.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use std::sync::mpsc;
+/// use std::thread;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let (tx, rx) = mpsc::channel::<Vec<u8>>();
+///     let worker = thread::spawn(move || loop {
+///         let job = match rx.try_recv() {
+///             Ok(job) => job,
+///             Err(mpsc::TryRecvError::Empty) => {
+///                 jemalloc_ctl::thread::on_park();
+///                 match rx.recv() {
+///                     Ok(job) => job,
+///                     Err(_) => break,
+///                 }
+///             }
+///             Err(mpsc::TryRecvError::Disconnected) => break,
+///         };
+///         drop(job);
+///     });
+///
+///     tx.send(vec![0; 1024]).unwrap();
+///     drop(tx);
+///     worker.join().unwrap();
+/// }
+/// ```
+///
+/// [`idle`]: fn.idle.html
+/// [`tcache_flush`]: fn.tcache_flush.html
+pub fn on_park() {
+    static IDLE_MIB: OnceLock<Option<Idle>> = OnceLock::new();
+
+    match *IDLE_MIB.get_or_init(|| Idle::new().ok()) {
+        Some(idle) => {
+            let _ = idle.idle();
+        }
+        None => {
+            let _ = tcache_flush();
+        }
+    }
+}
+
+const PEAK_READ: *const c_char = b"thread.peak.read\0" as *const _ as *const _;
+
+/// Returns the peak number of bytes net allocated by the current thread since the last reset.
+///
+/// The net allocation is the number of bytes allocated minus the number deallocated, so memory
+/// allocated on one thread and freed on another makes the value drift. This was added in
+/// jemalloc 5.3.0; an error with the `ENOENT` OS error code is returned by older versions.
+///
+/// This corresponds to `thread.peak.read` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::thread::peak_reset().unwrap();
+///     drop(vec![0u8; 1024 * 1024]);
+///     assert!(jemalloc_ctl::thread::peak_read().unwrap() >= 1024 * 1024);
+/// }
+/// ```
+pub fn peak_read() -> io::Result<u64> {
+    unsafe { get(PEAK_READ) }
+}
+
+/// A type providing access to the peak number of bytes net allocated by the current thread since
+/// the last reset.
+///
+/// This corresponds to `thread.peak.read` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::PeakRead;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let peak = PeakRead::new().unwrap();
+///     println!("peak: {} bytes", peak.get().unwrap());
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct PeakRead([usize; 3]);
+
+impl PeakRead {
+    /// Returns a new `PeakRead`.
+    pub fn new() -> io::Result<PeakRead> {
+        let mut mib = [0; 3];
+        unsafe {
+            name_to_mib(PEAK_READ, &mut mib)?;
+        }
+        Ok(PeakRead(mib))
+    }
+
+    /// Returns the peak number of bytes net allocated by the current thread since the last reset.
+    pub fn get(&self) -> io::Result<u64> {
+        unsafe { get_mib(&self.0) }
+    }
+}
+
+const PEAK_RESET: *const c_char = b"thread.peak.reset\0" as *const _ as *const _;
+
+/// Resets the current thread's peak net allocation to its current net allocation.
+///
+/// This was added in jemalloc 5.3.0; an error with the `ENOENT` OS error code is returned by
+/// older versions.
+///
+/// This corresponds to `thread.peak.reset` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::thread::peak_reset().unwrap();
+/// }
+/// ```
+pub fn peak_reset() -> io::Result<()> {
+    unsafe { call(PEAK_RESET) }
+}
+
+/// A type providing the ability to reset the current thread's peak net allocation.
+///
+/// This corresponds to `thread.peak.reset` in jemalloc's API.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::PeakReset;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let reset = PeakReset::new().unwrap();
+///     reset.reset().unwrap();
+/// }
+/// ```
+#[derive(Copy, Clone)]
+pub struct PeakReset([usize; 3]);
+
+impl PeakReset {
+    /// Returns a new `PeakReset`.
+    pub fn new() -> io::Result<PeakReset> {
+        let mut mib = [0; 3];
+        unsafe {
+            name_to_mib(PEAK_RESET, &mut mib)?;
+        }
+        Ok(PeakReset(mib))
+    }
+
+    /// Resets the current thread's peak net allocation.
+    pub fn reset(&self) -> io::Result<()> {
+        unsafe { call_mib(&self.0) }
+    }
+}
+
+/// A thread-local pointer.
+///
+/// It is neither `Sync` nor `Send`.
+// NB we need *const here specifically since it's !Sync + !Send
+#[derive(Copy, Clone)]
+pub struct ThreadLocal<T>(*const T);
+
+impl<T> ThreadLocal<T>
+where
+    T: Copy,
+{
+    /// Returns the current value at the pointer.
+    #[inline]
+    pub fn get(&self) -> T {
+        unsafe { *self.0 }
+    }
+}
+
+/// Thread-local pointers to both of the current thread's allocation counters.
+///
+/// Like [`ThreadLocal`], it is neither `Sync` nor `Send`.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::ThreadCounters;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let counters = ThreadCounters::new().unwrap();
+///
+///     let (_, _, a) = counters.get();
+///     let buf = vec![0u8; 1024 * 1024];
+///     let (_, _, b) = counters.get();
+///     drop(buf);
+///
+///     assert!(b - a >= 1024 * 1024);
+/// }
+/// ```
+///
+/// [`ThreadLocal`]: struct.ThreadLocal.html
+#[derive(Copy, Clone)]
+pub struct ThreadCounters {
+    allocated: ThreadLocal<u64>,
+    deallocated: ThreadLocal<u64>,
+}
+
+impl ThreadCounters {
+    /// Returns the counters of the current thread.
+    pub fn new() -> io::Result<ThreadCounters> {
+        Ok(ThreadCounters {
+            allocated: allocatedp()?,
+            deallocated: deallocatedp()?,
+        })
+    }
+
+    /// Returns the total number of bytes allocated and deallocated by the thread, and the
+    /// difference between them.
+    #[inline]
+    pub fn get(&self) -> (u64, u64, i64) {
+        let allocated = self.allocated.get();
+        let deallocated = self.deallocated.get();
+        (
+            allocated,
+            deallocated,
+            allocated.wrapping_sub(deallocated) as i64,
+        )
+    }
+}
+
+/// Byte counts allocated and deallocated by a thread over some period.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Allocations {
+    /// The number of bytes allocated.
+    pub allocated: u64,
+
+    /// The number of bytes deallocated.
+    pub deallocated: u64,
+}
+
+impl Allocations {
+    /// Returns the net change in the number of bytes allocated.
+    ///
+    /// This is negative if more bytes were deallocated than allocated.
+    pub fn net(&self) -> i64 {
+        self.allocated.wrapping_sub(self.deallocated) as i64
+    }
+}
+
+/// Runs a closure, returning its result along with the number of bytes it allocated and
+/// deallocated on the current thread.
+///
+/// Allocations made by other threads, including those spawned by the closure, are not counted.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let (buf, allocations) = jemalloc_ctl::thread::measure(|| vec![0u8; 1024 * 1024]).unwrap();
+///
+///     assert_eq!(buf.len(), 1024 * 1024);
+///     assert!(allocations.allocated >= 1024 * 1024);
+///     assert!(allocations.net() >= 1024 * 1024);
+/// }
+/// ```
+pub fn measure<F, T>(f: F) -> io::Result<(T, Allocations)>
+where
+    F: FnOnce() -> T,
+{
+    let scope = AllocationScope::new()?;
+    let value = f();
+    Ok((value, scope.allocations()))
+}
+
+/// A guard measuring the number of bytes allocated and deallocated by the current thread since it
+/// was created.
+///
+/// Like [`ThreadLocal`], it is neither `Sync` nor `Send`.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use jemalloc_ctl::thread::AllocationScope;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let scope = AllocationScope::new().unwrap();
+///
+///     let buf = vec![0u8; 1024 * 1024];
+///     drop(buf);
+///
+///     let allocations = scope.allocations();
+///     assert!(allocations.allocated >= 1024 * 1024);
+///     assert_eq!(allocations.allocated, allocations.deallocated);
+///     assert_eq!(allocations.net(), 0);
+/// }
+/// ```
+///
+/// [`ThreadLocal`]: struct.ThreadLocal.html
+pub struct AllocationScope {
+    counters: ThreadCounters,
+    start: Allocations,
+}
+
+impl AllocationScope {
+    /// Returns a new `AllocationScope` starting at the current thread's counters.
+    pub fn new() -> io::Result<AllocationScope> {
+        let counters = ThreadCounters::new()?;
+        let (allocated, deallocated, _) = counters.get();
+        Ok(AllocationScope {
+            counters,
+            start: Allocations {
+                allocated,
+                deallocated,
+            },
+        })
+    }
+
+    /// Returns the number of bytes allocated and deallocated by the current thread since the scope
+    /// was created.
+    pub fn allocations(&self) -> Allocations {
+        let (allocated, deallocated, _) = self.counters.get();
+        Allocations {
+            allocated: allocated.wrapping_sub(self.start.allocated),
+            deallocated: deallocated.wrapping_sub(self.start.deallocated),
+        }
+    }
+}
+
+/// The peak net allocation of a closure, as returned by [`measure_peak`].
+///
+/// [`measure_peak`]: fn.measure_peak.html
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Peak {
+    /// The peak number of bytes net allocated, relative to the start of the closure.
+    pub bytes: u64,
+
+    /// If set, `thread.peak` is not supported by the jemalloc version, and `bytes` is the net
+    /// allocation when the closure returned instead. This is a lower bound of the true peak.
+    pub estimated: bool,
+}
+
+/// Runs a closure, returning its result along with the peak number of bytes it net allocated on
+/// the current thread.
+///
+/// On jemalloc versions before 5.3.0, which do not track peak allocation, an estimate is derived
+/// from the thread's allocation counters. See [`Peak`] for details.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let (buf, peak) = jemalloc_ctl::thread::measure_peak(|| vec![0u8; 1024 * 1024]).unwrap();
+///
+///     assert!(peak.bytes >= buf.len() as u64);
+/// }
+/// ```
+///
+/// [`Peak`]: struct.Peak.html
+pub fn measure_peak<F, T>(f: F) -> io::Result<(T, Peak)>
+where
+    F: FnOnce() -> T,
+{
+    let read = match PeakRead::new() {
+        Ok(read) => read,
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => {
+            let (value, allocations) = measure(f)?;
+            let peak = Peak {
+                bytes: allocations.net().max(0) as u64,
+                estimated: true,
+            };
+            return Ok((value, peak));
+        }
+        Err(e) => return Err(e),
+    };
+
+    let reset = PeakReset::new()?;
+    reset.reset()?;
+    // the peak is relative to the net allocation at the time of the reset
+    let value = f();
+    let peak = Peak {
+        bytes: read.get()?,
+        estimated: false,
+    };
+    Ok((value, peak))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use arenas;
+    use std::os::raw::c_uint;
+
+    #[test]
+    fn on_park_flushes_tcache() {
+        const ARENAS_CREATE: *const c_char = b"arenas.create\0" as *const _ as *const _;
+        const THREAD_ARENA: *const c_char = b"thread.arena\0" as *const _ as *const _;
+        const NFLUSHES: *const c_char = b"stats.arenas.0.bins.0.nflushes\0" as *const _ as *const _;
+
+        // run on a thread bound to a fresh arena so that no other thread's flushes are counted
+        thread::spawn(|| unsafe {
+            let arena = get::<c_uint>(ARENAS_CREATE).unwrap();
+            set(THREAD_ARENA, arena).unwrap();
+
+            let boxes = (0..32).map(Box::new).collect::<Vec<Box<u64>>>();
+            let bin = match arenas::locate(&*boxes[0] as *const u64 as *const u8) {
+                Ok(arenas::Location {
+                    size_class: arenas::SizeClass::Small { bin },
+                    ..
+                }) => bin,
+                other => panic!("unexpected location {:?}", other),
+            };
+            drop(boxes);
+
+            let mut mib = [0; 6];
+            name_to_mib(NFLUSHES, &mut mib).unwrap();
+            mib[2] = arena as usize;
+            mib[4] = bin as usize;
+
+            ::epoch().unwrap();
+            let before = get_mib::<u64>(&mib).unwrap();
+            on_park();
+            ::epoch().unwrap();
+            assert!(get_mib::<u64>(&mib).unwrap() > before);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn peak() {
+        let (buf, peak) = measure_peak(|| {
+            drop(vec![0u8; 4 * 1024 * 1024]);
+            vec![0u8; 1024 * 1024]
+        })
+        .unwrap();
+
+        assert!(peak.bytes >= buf.len() as u64);
+        if !peak.estimated {
+            assert!(peak.bytes >= 4 * 1024 * 1024);
+        }
+    }
+
+    #[test]
+    fn sanitize() {
+        assert_eq!(sanitize_name("worker-1").to_str().unwrap(), "worker-1");
+        assert_eq!(sanitize_name("a b\tc").to_str().unwrap(), "a b\tc");
+        assert_eq!(sanitize_name("naïve\n\0").to_str().unwrap(), "na_ve__");
+    }
+}
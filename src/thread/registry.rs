@@ -0,0 +1,253 @@
+//! A cross-thread registry of per-thread allocation counters.
+//!
+//! jemalloc's per-thread counters (see [`thread::allocatedp`]) can only be read by their own
+//! thread, so [`ThreadLocal`] pointers cannot be sent between threads. Threads which [`register`]
+//! themselves here instead copy their counters into the registry with [`publish`], and any thread
+//! can read the most recently published counters of all live registered threads with
+//! [`threads`].
+//!
+//! [`thread::allocatedp`]: ../fn.allocatedp.html
+//! [`ThreadLocal`]: ../struct.ThreadLocal.html
+//! [`register`]: fn.register.html
+//! [`publish`]: fn.publish.html
+//! [`threads`]: fn.threads.html
+use std::cell::RefCell;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+use thread::{Allocations, ThreadCounters};
+
+// the counters most recently published by a thread
+#[derive(Default)]
+struct Snapshot {
+    allocated: AtomicU64,
+    deallocated: AtomicU64,
+}
+
+struct Entry {
+    id: ThreadId,
+    name: Option<String>,
+    snapshot: Arc<Snapshot>,
+}
+
+static REGISTRY: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+fn registry() -> MutexGuard<'static, Vec<Entry>> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// publishes the thread's counters, and removes its entry when the thread exits
+struct Registration {
+    id: ThreadId,
+    counters: ThreadCounters,
+    snapshot: Arc<Snapshot>,
+}
+
+impl Registration {
+    fn publish(&self) {
+        let (allocated, deallocated, _) = self.counters.get();
+        self.snapshot.allocated.store(allocated, Ordering::Relaxed);
+        self.snapshot
+            .deallocated
+            .store(deallocated, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        registry().retain(|e| e.id != self.id);
+    }
+}
+
+thread_local! {
+    static REGISTRATION: RefCell<Option<Registration>> = const { RefCell::new(None) };
+}
+
+/// The allocation counters of a registered thread.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadAllocations {
+    /// The ID of the thread.
+    pub id: ThreadId,
+
+    /// The name of the thread, if it has one.
+    pub name: Option<String>,
+
+    /// The total number of bytes allocated and deallocated by the thread.
+    pub allocations: Allocations,
+}
+
+/// Registers the current thread's allocation counters.
+///
+/// The thread's current counters are published immediately. The thread remains registered until
+/// it exits or calls [`unregister`]. Registering an already registered thread only publishes its
+/// counters.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// use std::thread;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let worker = thread::Builder::new()
+///         .name("worker".to_string())
+///         .spawn(|| {
+///             jemalloc_ctl::thread::registry::register().unwrap();
+///             for _ in 0..10 {
+///                 // ...
+///                 jemalloc_ctl::thread::registry::publish();
+///             }
+///         })
+///         .unwrap();
+///     worker.join().unwrap();
+/// }
+/// ```
+///
+/// [`unregister`]: fn.unregister.html
+pub fn register() -> io::Result<()> {
+    let counters = ThreadCounters::new()?;
+
+    REGISTRATION
+        .try_with(|registration| {
+            let mut registration = registration.borrow_mut();
+            if registration.is_none() {
+                let current = thread::current();
+                let snapshot = Arc::new(Snapshot::default());
+                registry().push(Entry {
+                    id: current.id(),
+                    name: current.name().map(|s| s.to_string()),
+                    snapshot: snapshot.clone(),
+                });
+                *registration = Some(Registration {
+                    id: current.id(),
+                    counters,
+                    snapshot,
+                });
+            }
+            if let Some(ref registration) = *registration {
+                registration.publish();
+            }
+        })
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "thread is exiting"))
+}
+
+/// Publishes the current thread's allocation counters to the registry.
+///
+/// [`threads`] reports the counters as of each thread's most recent call, so registered threads
+/// should call this periodically, for example between units of work. Publishing is two atomic
+/// stores. Does nothing if the thread is not registered.
+///
+/// [`threads`]: fn.threads.html
+pub fn publish() {
+    let _ = REGISTRATION.try_with(|registration| {
+        if let Some(ref registration) = *registration.borrow() {
+            registration.publish();
+        }
+    });
+}
+
+/// Unregisters the current thread's allocation counters.
+///
+/// Does nothing if the thread is not registered.
+pub fn unregister() {
+    let _ = REGISTRATION.try_with(|registration| registration.borrow_mut().take());
+}
+
+/// Returns the most recently published allocation counters of all live registered threads.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     jemalloc_ctl::thread::registry::register().unwrap();
+///
+///     let mut threads = jemalloc_ctl::thread::registry::threads();
+///     threads.sort_by_key(|t| std::cmp::Reverse(t.allocations.allocated));
+///     for thread in threads.iter().take(10) {
+///         println!(
+///             "{:?} ({}): {} bytes allocated, {} net",
+///             thread.id,
+///             thread.name.as_ref().map_or("<unnamed>", |s| &**s),
+///             thread.allocations.allocated,
+///             thread.allocations.net(),
+///         );
+///     }
+/// }
+/// ```
+pub fn threads() -> Vec<ThreadAllocations> {
+    registry()
+        .iter()
+        .map(|e| ThreadAllocations {
+            id: e.id,
+            name: e.name.clone(),
+            allocations: Allocations {
+                allocated: e.snapshot.allocated.load(Ordering::Relaxed),
+                deallocated: e.snapshot.deallocated.load(Ordering::Relaxed),
+            },
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn lifecycle() {
+        let (registered_tx, registered_rx) = mpsc::channel();
+        let (exit_tx, exit_rx) = mpsc::channel::<()>();
+
+        let worker = thread::Builder::new()
+            .name("registry-test".to_string())
+            .spawn(move || {
+                register().unwrap();
+                register().unwrap();
+                let buf = vec![0u8; 1024 * 1024];
+                publish();
+                registered_tx.send(thread::current().id()).unwrap();
+                exit_rx.recv().unwrap();
+                drop(buf);
+            })
+            .unwrap();
+
+        let id = registered_rx.recv().unwrap();
+        let entries = threads()
+            .into_iter()
+            .filter(|t| t.id == id)
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name.as_ref().unwrap(), "registry-test");
+        assert!(entries[0].allocations.allocated >= 1024 * 1024);
+
+        exit_tx.send(()).unwrap();
+        worker.join().unwrap();
+        assert!(threads().iter().all(|t| t.id != id));
+    }
+
+    #[test]
+    fn explicit_unregister() {
+        thread::spawn(|| {
+            let id = thread::current().id();
+            register().unwrap();
+            assert!(threads().iter().any(|t| t.id == id));
+            unregister();
+            assert!(threads().iter().all(|t| t.id != id));
+        })
+        .join()
+        .unwrap();
+    }
+}
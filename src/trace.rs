@@ -0,0 +1,217 @@
+//! Per-span allocation accounting for `tracing`.
+//!
+//! [`AllocationLayer`] samples the current thread's allocation counters each time a span is
+//! entered and exited, and accumulates the difference in the span's extensions as a
+//! [`SpanAllocations`]. Counts are inclusive: a span's totals include those of any child spans
+//! entered while it was. The totals can optionally be emitted as an event when the span closes.
+//!
+//! This module requires the `tracing` Cargo feature.
+//!
+//! [`AllocationLayer`]: struct.AllocationLayer.html
+//! [`SpanAllocations`]: struct.SpanAllocations.html
+use std::io;
+use tracing::span::{Attributes, Id};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use thread::{AllocatedP, Allocations, DeallocatedP};
+
+/// The number of bytes allocated and deallocated while a span was entered.
+///
+/// The `AllocationLayer` stores this in each span's extensions, where other layers can read it.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpanAllocations(pub Allocations);
+
+// the counter values when the span was last entered
+struct Entered(Option<(u64, u64)>);
+
+/// A `tracing_subscriber` layer which tracks the bytes allocated and deallocated within each span.
+///
+/// A span entered on multiple threads at the same time is only tracked on one of them.
+///
+/// # Examples
+///
+/// ```
+/// extern crate jemallocator;
+/// extern crate jemalloc_ctl;
+/// extern crate tracing;
+/// extern crate tracing_subscriber;
+///
+/// use jemalloc_ctl::trace::AllocationLayer;
+/// use tracing::Level;
+/// use tracing_subscriber::layer::SubscriberExt;
+///
+/// #[global_allocator]
+/// static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+///
+/// fn main() {
+///     let layer = AllocationLayer::new().unwrap().with_close_events(Level::DEBUG);
+///     let subscriber = tracing_subscriber::registry().with(layer);
+///
+///     tracing::subscriber::with_default(subscriber, || {
+///         let _buf = tracing::info_span!("request").in_scope(|| vec![0u8; 1024]);
+///     });
+/// }
+/// ```
+pub struct AllocationLayer {
+    allocatedp: AllocatedP,
+    deallocatedp: DeallocatedP,
+    close_events: Option<Level>,
+}
+
+impl AllocationLayer {
+    /// Returns a new `AllocationLayer`.
+    ///
+    /// Close events are disabled by default.
+    pub fn new() -> io::Result<AllocationLayer> {
+        let allocatedp = AllocatedP::new()?;
+        let deallocatedp = DeallocatedP::new()?;
+        // make sure the counters are available so callbacks don't have to deal with errors
+        allocatedp.get()?;
+        deallocatedp.get()?;
+
+        Ok(AllocationLayer {
+            allocatedp,
+            deallocatedp,
+            close_events: None,
+        })
+    }
+
+    /// Enables an event at the specified level when each span closes.
+    ///
+    /// The event has `span`, `allocated`, `deallocated`, and `net` fields.
+    pub fn with_close_events(mut self, level: Level) -> AllocationLayer {
+        self.close_events = Some(level);
+        self
+    }
+
+    fn counters(&self) -> Option<(u64, u64)> {
+        match (self.allocatedp.get(), self.deallocatedp.get()) {
+            (Ok(allocated), Ok(deallocated)) => Some((allocated.get(), deallocated.get())),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! close_event {
+    ($level:expr, $($fields:tt)*) => {
+        match $level {
+            Level::ERROR => tracing::event!(target: "jemalloc_ctl", Level::ERROR, $($fields)*),
+            Level::WARN => tracing::event!(target: "jemalloc_ctl", Level::WARN, $($fields)*),
+            Level::INFO => tracing::event!(target: "jemalloc_ctl", Level::INFO, $($fields)*),
+            Level::DEBUG => tracing::event!(target: "jemalloc_ctl", Level::DEBUG, $($fields)*),
+            _ => tracing::event!(target: "jemalloc_ctl", Level::TRACE, $($fields)*),
+        }
+    };
+}
+
+impl<S> Layer<S> for AllocationLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            extensions.insert(SpanAllocations::default());
+            extensions.insert(Entered(None));
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(entered) = span.extensions_mut().get_mut::<Entered>() {
+                entered.0 = self.counters();
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let end = self.counters();
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            let start = match extensions.get_mut::<Entered>() {
+                Some(entered) => entered.0.take(),
+                None => None,
+            };
+            if let (Some(start), Some(end), Some(allocations)) =
+                (start, end, extensions.get_mut::<SpanAllocations>())
+            {
+                let allocations = &mut allocations.0;
+                allocations.allocated = allocations
+                    .allocated
+                    .wrapping_add(end.0.wrapping_sub(start.0));
+                allocations.deallocated = allocations
+                    .deallocated
+                    .wrapping_add(end.1.wrapping_sub(start.1));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let level = match self.close_events {
+            Some(level) => level,
+            None => return,
+        };
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let allocations = match span.extensions().get::<SpanAllocations>() {
+            Some(allocations) => allocations.0,
+            None => return,
+        };
+
+        close_event!(
+            level,
+            span = span.name(),
+            allocated = allocations.allocated,
+            deallocated = allocations.deallocated,
+            net = allocations.net(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    struct Collect(Arc<Mutex<Vec<(&'static str, Allocations)>>>);
+
+    impl<S> Layer<S> for Collect
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            let allocations = span.extensions().get::<SpanAllocations>().unwrap().0;
+            self.0.lock().unwrap().push((span.name(), allocations));
+        }
+    }
+
+    #[test]
+    fn nested_spans() {
+        let spans = Arc::new(Mutex::new(vec![]));
+        let subscriber = tracing_subscriber::registry()
+            .with(AllocationLayer::new().unwrap())
+            .with(Collect(spans.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("outer").in_scope(|| {
+                let _a = vec![0u8; 4096];
+                tracing::info_span!("inner").in_scope(|| vec![0u8; 8192]);
+            });
+        });
+
+        let spans = spans.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].0, "inner");
+        assert!(spans[0].1.allocated >= 8192);
+        assert!(spans[0].1.deallocated < 8192);
+        assert_eq!(spans[1].0, "outer");
+        assert!(spans[1].1.allocated >= 4096 + 8192);
+        assert!(spans[1].1.deallocated >= 4096 + 8192);
+    }
+}